@@ -0,0 +1 @@
+ALTER TABLE job_updates ADD COLUMN protocol TEXT NOT NULL DEFAULT 'sv1';
@@ -0,0 +1 @@
+DROP TABLE extranonce_updates;
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS extranonce_updates (
+    id                         SERIAL    PRIMARY KEY,
+    timestamp                  TIMESTAMP NOT NULL,
+    pool                       TEXT      NOT NULL,
+    extranonce1                BYTEA     NOT NULL,
+    extranonce2_size           INTEGER   NOT NULL,
+    previous_extranonce1       BYTEA     NOT NULL,
+    previous_extranonce2_size  INTEGER   NOT NULL
+)
@@ -0,0 +1,2 @@
+DROP TABLE difficulty_updates;
+ALTER TABLE job_updates DROP COLUMN difficulty;
@@ -0,0 +1,9 @@
+ALTER TABLE job_updates ADD COLUMN difficulty DOUBLE PRECISION;
+
+CREATE TABLE IF NOT EXISTS difficulty_updates (
+    id                   SERIAL            PRIMARY KEY,
+    timestamp            TIMESTAMP         NOT NULL,
+    pool                 TEXT              NOT NULL,
+    difficulty           DOUBLE PRECISION  NOT NULL,
+    previous_difficulty  DOUBLE PRECISION
+);
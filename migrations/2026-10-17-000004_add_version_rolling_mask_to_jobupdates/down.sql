@@ -0,0 +1 @@
+ALTER TABLE job_updates DROP COLUMN version_rolling_mask;
@@ -0,0 +1 @@
+ALTER TABLE job_updates ADD COLUMN version_rolling_mask BIGINT;
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS tls_updates (
+    id                       SERIAL     PRIMARY KEY,
+    timestamp                TIMESTAMP  NOT NULL,
+    pool                     TEXT       NOT NULL,
+    protocol_version         TEXT       NOT NULL,
+    cipher_suite             TEXT       NOT NULL,
+    certificate_fingerprint  TEXT       NOT NULL
+)
@@ -0,0 +1 @@
+ALTER TABLE job_updates ADD COLUMN endpoint TEXT NOT NULL DEFAULT '';
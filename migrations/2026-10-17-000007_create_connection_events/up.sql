@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS connection_events (
+    id         SERIAL     PRIMARY KEY,
+    timestamp  TIMESTAMP  NOT NULL,
+    pool       TEXT       NOT NULL,
+    endpoint   TEXT       NOT NULL,
+    kind       TEXT       NOT NULL,
+    address    TEXT,
+    message    TEXT
+)
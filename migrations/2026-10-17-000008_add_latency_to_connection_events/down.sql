@@ -0,0 +1 @@
+ALTER TABLE connection_events DROP COLUMN latency_ms;
@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS coinbase_outputs (
+    id             SERIAL     PRIMARY KEY,
+    job_update_id  INTEGER    NOT NULL REFERENCES job_updates (id) ON DELETE CASCADE,
+    output_index   INTEGER    NOT NULL,
+    value          BIGINT     NOT NULL,
+    script_type    TEXT       NOT NULL,
+    address        TEXT,
+    script         BYTEA      NOT NULL
+);
+CREATE INDEX IF NOT EXISTS coinbase_outputs_job_update_id ON coinbase_outputs (job_update_id);
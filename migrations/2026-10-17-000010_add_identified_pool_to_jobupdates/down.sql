@@ -0,0 +1,3 @@
+ALTER TABLE job_updates DROP COLUMN pool_mismatch;
+ALTER TABLE job_updates DROP COLUMN identified_by;
+ALTER TABLE job_updates DROP COLUMN identified_pool;
@@ -0,0 +1,3 @@
+ALTER TABLE job_updates ADD COLUMN identified_pool TEXT;
+ALTER TABLE job_updates ADD COLUMN identified_by TEXT;
+ALTER TABLE job_updates ADD COLUMN pool_mismatch BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,2 @@
+ALTER TABLE job_updates DROP COLUMN witness_commitment_changed;
+ALTER TABLE job_updates DROP COLUMN witness_commitment;
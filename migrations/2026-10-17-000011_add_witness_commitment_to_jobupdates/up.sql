@@ -0,0 +1,2 @@
+ALTER TABLE job_updates ADD COLUMN witness_commitment BYTEA;
+ALTER TABLE job_updates ADD COLUMN witness_commitment_changed BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,416 @@
+//! A local HTTP admin API to inspect the pools and to disable, enable and reconnect them.
+//! Requests must carry the configured token as `Authorization: Bearer <token>`.
+//!
+//! - `GET /pools` lists the pools with their connection state.
+//! - `GET /pools/<name>/notify` shows the last raw mining.notify of a Stratum v1 pool.
+//! - `POST /pools/<name>/enable`, `/disable` and `/reconnect` control the pool's task.
+
+use crate::connection::PoolStatuses;
+use crate::http::{self, ReadError};
+use crate::supervisor::PoolAction;
+use async_channel::{bounded, Sender};
+use async_std::io::WriteExt;
+use async_std::net::{TcpListener, TcpStream};
+use async_std::{prelude::*, task};
+use log::{debug, error, info, warn};
+use serde::Serialize;
+
+/// A command for the pool tasks, answered on the included channel.
+pub enum AdminCommand {
+    /// Lists the configured pools and whether they are enabled.
+    List(Sender<Vec<(String, bool)>>),
+    /// Controls a pool's task. Answered with false if there's no such pool.
+    Control(String, PoolAction, Sender<bool>),
+}
+
+#[derive(Debug, Serialize)]
+struct PoolJson {
+    name: String,
+    enabled: bool,
+    state: Option<String>,
+    state_timestamp: Option<i64>,
+    failures: u32,
+    rejections: u32,
+    rejection_message: Option<String>,
+    last_notify_timestamp: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct NotifyJson {
+    pool_name: String,
+    timestamp: i64,
+    line: String,
+}
+
+/// The parts of a HTTP request the admin API uses.
+#[derive(Debug, Default, PartialEq)]
+struct Request {
+    method: String,
+    path: String,
+    authorization: Option<String>,
+}
+
+/// A response status and JSON body.
+type Response = (&'static str, String);
+
+/// Serves the admin API until the command channel is closed.
+pub async fn serve(
+    address: &str,
+    token: String,
+    statuses: PoolStatuses,
+    commands: Sender<AdminCommand>,
+) {
+    info!("Starting admin server on {}", address);
+    let listener = match TcpListener::bind(address).await {
+        Ok(l) => l,
+        Err(e) => {
+            error!("Could not start admin server on {}: {}", address, e);
+            return;
+        }
+    };
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        match stream {
+            Ok(stream) => {
+                let (token, statuses, commands) =
+                    (token.clone(), statuses.clone(), commands.clone());
+                task::spawn(async move {
+                    if let Err(e) = handle_connection(stream, &token, &statuses, &commands).await {
+                        debug!("Could not answer admin request: {}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("Failed to accept incoming admin connection: {}", e),
+        }
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    token: &str,
+    statuses: &PoolStatuses,
+    commands: &Sender<AdminCommand>,
+) -> std::io::Result<()> {
+    let (status, body) = match http::read_request_head(&stream).await {
+        Ok(head) => {
+            let request = Request {
+                authorization: head.header("authorization").map(str::to_string),
+                method: head.method,
+                path: head.path,
+            };
+            handle_request(&request, token, statuses, commands).await
+        }
+        Err(ReadError::Io(e)) => return Err(e),
+        Err(e) => error_response(e.status(), &e.to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    let mut stream = stream;
+    stream.write_all(response.as_bytes()).await?;
+    stream.flush().await
+}
+
+fn error_response(status: &'static str, message: &str) -> Response {
+    (status, serde_json::json!({ "error": message }).to_string())
+}
+
+async fn handle_request(
+    request: &Request,
+    token: &str,
+    statuses: &PoolStatuses,
+    commands: &Sender<AdminCommand>,
+) -> Response {
+    let authorized = request
+        .authorization
+        .as_deref()
+        .and_then(|a| a.strip_prefix("Bearer "))
+        .is_some_and(|t| tokens_match(t, token));
+    if !authorized {
+        return error_response("401 Unauthorized", "missing or wrong token");
+    }
+
+    let segments: Vec<Option<String>> = request
+        .path
+        .trim_start_matches('/')
+        .split('/')
+        .map(percent_decode)
+        .collect();
+    let Some(segments) = segments.into_iter().collect::<Option<Vec<String>>>() else {
+        return error_response("400 Bad Request", "invalid path");
+    };
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["pools"]) => list_pools(statuses, commands).await,
+        ("GET", ["pools", name, "notify"]) => {
+            if !pool_exists(name, commands).await {
+                return error_response("404 Not Found", "no such pool");
+            }
+            match statuses.get(name).and_then(|s| s.last_notify) {
+                Some((received, line)) => (
+                    "200 OK",
+                    json(&NotifyJson {
+                        pool_name: name.to_string(),
+                        timestamp: received.timestamp(),
+                        line,
+                    }),
+                ),
+                None => error_response("404 Not Found", "no mining.notify received yet"),
+            }
+        }
+        ("POST", ["pools", name, action]) => {
+            let action = match *action {
+                "enable" => PoolAction::Enable,
+                "disable" => PoolAction::Disable,
+                "reconnect" => PoolAction::Reconnect,
+                _ => return error_response("404 Not Found", "not found"),
+            };
+            let (sender, receiver) = bounded(1);
+            let command = AdminCommand::Control(name.to_string(), action, sender);
+            if commands.send(command).await.is_err() {
+                return error_response("503 Service Unavailable", "shutting down");
+            }
+            match receiver.recv().await {
+                Ok(true) => list_pools(statuses, commands).await,
+                Ok(false) => error_response("404 Not Found", "no such pool"),
+                Err(_) => error_response("503 Service Unavailable", "shutting down"),
+            }
+        }
+        _ => error_response("404 Not Found", "not found"),
+    }
+}
+
+async fn configured_pools(commands: &Sender<AdminCommand>) -> Option<Vec<(String, bool)>> {
+    let (sender, receiver) = bounded(1);
+    commands.send(AdminCommand::List(sender)).await.ok()?;
+    receiver.recv().await.ok()
+}
+
+async fn pool_exists(name: &str, commands: &Sender<AdminCommand>) -> bool {
+    configured_pools(commands)
+        .await
+        .is_some_and(|pools| pools.iter().any(|(n, _)| n == name))
+}
+
+async fn list_pools(statuses: &PoolStatuses, commands: &Sender<AdminCommand>) -> Response {
+    let Some(pools) = configured_pools(commands).await else {
+        return error_response("503 Service Unavailable", "shutting down");
+    };
+    let pools: Vec<PoolJson> = pools
+        .into_iter()
+        .map(|(name, enabled)| {
+            let status = statuses.get(&name);
+            PoolJson {
+                enabled,
+                state: status.as_ref().map(|s| s.state.to_string()),
+                state_timestamp: status.as_ref().map(|s| s.since.timestamp()),
+                failures: status.as_ref().map(|s| s.failures).unwrap_or_default(),
+                rejections: status.as_ref().map(|s| s.rejections).unwrap_or_default(),
+                rejection_message: status.as_ref().and_then(|s| s.rejection_message.clone()),
+                last_notify_timestamp: status
+                    .as_ref()
+                    .and_then(|s| s.last_notify.as_ref())
+                    .map(|(received, _)| received.timestamp()),
+                name,
+            }
+        })
+        .collect();
+    ("200 OK", json(&pools))
+}
+
+fn json<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).unwrap_or_default()
+}
+
+/// Compares the tokens in constant time for tokens of the same length.
+fn tokens_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+/// Decodes %XX escapes in a path segment, e.g. spaces in pool names.
+fn percent_decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::ConnectionState;
+    use chrono::prelude::*;
+    use serde_json::Value;
+
+    const TOKEN: &str = "secret";
+
+    /// Answers the admin commands for the enabled "Pool A" and the disabled "Pool B".
+    fn command_handler() -> (Sender<AdminCommand>, async_channel::Receiver<String>) {
+        let (sender, receiver) = async_channel::unbounded();
+        let (controlled_sender, controlled) = async_channel::unbounded();
+        task::spawn(async move {
+            while let Ok(command) = receiver.recv().await {
+                match command {
+                    AdminCommand::List(respond) => {
+                        let pools = vec![
+                            (String::from("Pool A"), true),
+                            (String::from("Pool B"), false),
+                        ];
+                        let _ = respond.send(pools).await;
+                    }
+                    AdminCommand::Control(name, action, respond) => {
+                        let known = name.starts_with("Pool ");
+                        let _ = controlled_sender
+                            .send(format!("{:?} {}", action, name))
+                            .await;
+                        let _ = respond.send(known).await;
+                    }
+                }
+            }
+        });
+        (sender, controlled)
+    }
+
+    fn request(method: &str, path: &str, token: Option<&str>) -> Request {
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            authorization: token.map(|t| format!("Bearer {}", t)),
+        }
+    }
+
+    #[async_std::test]
+    async fn reject_requests_without_the_token() {
+        let (commands, _) = command_handler();
+        let statuses = PoolStatuses::default();
+        for token in [None, Some("wrong"), Some("secre")] {
+            let (status, _) = handle_request(
+                &request("GET", "/pools", token),
+                TOKEN,
+                &statuses,
+                &commands,
+            )
+            .await;
+            assert_eq!(status, "401 Unauthorized");
+        }
+    }
+
+    #[async_std::test]
+    async fn list_and_control_pools() {
+        let (commands, controlled) = command_handler();
+        let statuses = PoolStatuses::default();
+        statuses.set_state("Pool A", ConnectionState::Live);
+        statuses.notified("Pool A", r#"{"method":"mining.notify"}"#, Utc::now());
+        statuses.set_state("Pool B", ConnectionState::Disabled);
+
+        let (status, body) = handle_request(
+            &request("GET", "/pools", Some(TOKEN)),
+            TOKEN,
+            &statuses,
+            &commands,
+        )
+        .await;
+        assert_eq!(status, "200 OK");
+        let pools: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(pools[0]["name"], "Pool A");
+        assert_eq!(pools[0]["enabled"], true);
+        assert_eq!(pools[0]["state"], "live");
+        assert!(pools[0]["last_notify_timestamp"].is_i64());
+        assert_eq!(pools[1]["enabled"], false);
+        assert_eq!(pools[1]["state"], "disabled");
+
+        let (status, body) = handle_request(
+            &request("GET", "/pools/Pool%20A/notify", Some(TOKEN)),
+            TOKEN,
+            &statuses,
+            &commands,
+        )
+        .await;
+        assert_eq!(status, "200 OK");
+        let notify: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(notify["line"], r#"{"method":"mining.notify"}"#);
+        let (status, _) = handle_request(
+            &request("GET", "/pools/Pool%20B/notify", Some(TOKEN)),
+            TOKEN,
+            &statuses,
+            &commands,
+        )
+        .await;
+        assert_eq!(status, "404 Not Found");
+
+        let (status, _) = handle_request(
+            &request("POST", "/pools/Pool%20B/enable", Some(TOKEN)),
+            TOKEN,
+            &statuses,
+            &commands,
+        )
+        .await;
+        assert_eq!(status, "200 OK");
+        assert_eq!(controlled.recv().await.unwrap(), "Enable Pool B");
+        let (status, _) = handle_request(
+            &request("POST", "/pools/Unknown/reconnect", Some(TOKEN)),
+            TOKEN,
+            &statuses,
+            &commands,
+        )
+        .await;
+        assert_eq!(status, "404 Not Found");
+        assert_eq!(controlled.recv().await.unwrap(), "Reconnect Unknown");
+        let (status, _) = handle_request(
+            &request("POST", "/pools/Pool%20A/restart", Some(TOKEN)),
+            TOKEN,
+            &statuses,
+            &commands,
+        )
+        .await;
+        assert_eq!(status, "404 Not Found");
+    }
+
+    #[async_std::test]
+    async fn reject_requests_with_too_large_headers() {
+        let (commands, _) = command_handler();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = handle_connection(stream, TOKEN, &PoolStatuses::default(), &commands).await;
+        });
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        // one byte too many, all of which the server reads, so it doesn't reset the connection
+        let mut request = format!(
+            "GET /pools HTTP/1.1\r\nAuthorization: Bearer {}\r\nX: ",
+            TOKEN
+        );
+        request.push_str(&"a".repeat(http::MAX_HEAD_BYTES + 1 - request.len()));
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 431 "));
+    }
+
+    #[test]
+    fn decode_path_segments() {
+        assert_eq!(percent_decode("Pool%20A").unwrap(), "Pool A");
+        assert_eq!(percent_decode("plain").unwrap(), "plain");
+        assert_eq!(percent_decode("%2"), None);
+        assert_eq!(percent_decode("%zz"), None);
+    }
+}
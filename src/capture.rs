@@ -0,0 +1,255 @@
+use async_channel::{unbounded, Sender};
+use chrono::prelude::*;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Where and how the raw stratum lines of pools with `capture = true` are written.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct CaptureConfig {
+    /// Directory the capture files are written to. Created if it doesn't exist.
+    pub directory: String,
+    /// Size in bytes after which a new capture file is started.
+    pub max_file_size: u64,
+    /// Age in seconds after which a new capture file is started.
+    pub max_file_age: u64,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        CaptureConfig {
+            directory: String::from("captures"),
+            max_file_size: 64 * 1024 * 1024,
+            max_file_age: 3600,
+        }
+    }
+}
+
+impl CaptureConfig {
+    pub fn is_valid(&self) -> bool {
+        !self.directory.is_empty() && self.max_file_size > 0 && self.max_file_age > 0
+    }
+}
+
+/// Whether a captured line was received from or sent to the pool.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    In,
+    Out,
+}
+
+/// A captured stratum line. Written as one JSON object per line.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CaptureRecord {
+    /// RFC 3339 receive or send time with nanosecond precision.
+    pub timestamp: String,
+    pub pool: String,
+    pub endpoint: String,
+    /// Identifies the connection the line was sent or received on.
+    pub connection_id: String,
+    pub direction: Direction,
+    /// The line without the trailing newline.
+    pub line: String,
+}
+
+impl CaptureRecord {
+    pub fn new(
+        pool: &str,
+        endpoint: &str,
+        connection_id: &str,
+        direction: Direction,
+        line: &str,
+        timestamp: DateTime<Utc>,
+    ) -> CaptureRecord {
+        CaptureRecord {
+            timestamp: timestamp.to_rfc3339_opts(SecondsFormat::Nanos, true),
+            pool: pool.to_string(),
+            endpoint: endpoint.to_string(),
+            connection_id: connection_id.to_string(),
+            direction,
+            line: line.trim_end_matches(['\r', '\n']).to_string(),
+        }
+    }
+}
+
+/// A random identifier for a new connection.
+pub fn new_connection_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+struct CaptureFile {
+    file: File,
+    opened: Instant,
+    size: u64,
+}
+
+/// Writes the capture records of a pool to JSONL files, starting a new file when the
+/// current one becomes too large or too old.
+pub struct CaptureWriter {
+    config: CaptureConfig,
+    pool: String,
+    current: Option<CaptureFile>,
+    files_opened: u64,
+}
+
+impl CaptureWriter {
+    pub fn new(config: CaptureConfig, pool: &str) -> CaptureWriter {
+        CaptureWriter {
+            config,
+            pool: pool.to_string(),
+            current: None,
+            files_opened: 0,
+        }
+    }
+
+    pub fn write(&mut self, record: &CaptureRecord) -> io::Result<()> {
+        let mut line = serde_json::to_string(record)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        line.push('\n');
+
+        let rotate = self.current.as_ref().is_some_and(|current| {
+            (current.size > 0 && current.size + line.len() as u64 > self.config.max_file_size)
+                || current.opened.elapsed() >= Duration::from_secs(self.config.max_file_age)
+        });
+        if rotate || self.current.is_none() {
+            self.current = Some(self.open_file()?);
+        }
+        let current = self
+            .current
+            .as_mut()
+            .expect("a capture file should be open");
+        // written unbuffered, so the files are complete while we are running
+        current.file.write_all(line.as_bytes())?;
+        current.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn open_file(&mut self) -> io::Result<CaptureFile> {
+        fs::create_dir_all(&self.config.directory)?;
+        let pool: String = self
+            .pool
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let path: PathBuf = [
+            self.config.directory.clone(),
+            format!(
+                "{}-{}-{}.jsonl",
+                pool,
+                Utc::now().format("%Y%m%dT%H%M%SZ"),
+                self.files_opened
+            ),
+        ]
+        .iter()
+        .collect();
+        debug!("Writing stratum capture of '{}' to {:?}", self.pool, path);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        self.files_opened += 1;
+        Ok(CaptureFile {
+            size: file.metadata()?.len(),
+            file,
+            opened: Instant::now(),
+        })
+    }
+}
+
+/// Starts a thread writing the pool's capture records and returns the sender for the records.
+pub fn spawn_writer(config: CaptureConfig, pool: &str) -> Sender<CaptureRecord> {
+    let (sender, receiver) = unbounded::<CaptureRecord>();
+    let mut writer = CaptureWriter::new(config, pool);
+    thread::spawn(move || {
+        while let Ok(record) = receiver.recv_blocking() {
+            if let Err(e) = writer.write(&record) {
+                warn!("Could not write capture of '{}': {}", writer.pool, e);
+            }
+        }
+    });
+    sender
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+
+    #[test]
+    fn write_rotating_capture_files() {
+        let directory = std::env::temp_dir().join(format!(
+            "stratum-observer-test-capture-{}",
+            std::process::id()
+        ));
+        let config = CaptureConfig {
+            directory: directory.to_string_lossy().to_string(),
+            max_file_size: 450,
+            max_file_age: 3600,
+        };
+        let mut writer = CaptureWriter::new(config, "Test Pool/1");
+        let records: Vec<CaptureRecord> = (0..4)
+            .map(|i| {
+                CaptureRecord::new(
+                    "Test Pool/1",
+                    "127.0.0.1:3333",
+                    "0123456789abcdef",
+                    if i % 2 == 0 {
+                        Direction::Out
+                    } else {
+                        Direction::In
+                    },
+                    &format!(
+                        "{{\"id\":{},\"method\":\"mining.subscribe\",\"params\":[]}}\n",
+                        i
+                    ),
+                    Utc::now(),
+                )
+            })
+            .collect();
+        for record in records.iter() {
+            writer.write(record).unwrap();
+        }
+
+        let mut paths: Vec<PathBuf> = fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        paths.sort_by_key(|p| {
+            let name = p.file_stem().unwrap().to_string_lossy().to_string();
+            name.rsplit('-').next().unwrap().parse::<u64>().unwrap()
+        });
+        let read: Vec<CaptureRecord> = paths
+            .iter()
+            .flat_map(|path| {
+                assert!(path
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .starts_with("Test_Pool_1-"));
+                BufReader::new(File::open(path).unwrap())
+                    .lines()
+                    .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+                    .collect::<Vec<CaptureRecord>>()
+            })
+            .collect();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(paths.len(), 2);
+        assert_eq!(read, records);
+        assert!(!read[0].line.ends_with('\n'));
+        assert_eq!(read[0].direction, Direction::Out);
+        assert_eq!(
+            read[0].timestamp.len(),
+            "2026-10-17T00:00:00.000000000Z".len()
+        );
+    }
+}
@@ -1,19 +1,27 @@
-use crate::types::{JobUpdate, Pool};
-use crate::utils;
-use async_channel::{bounded, Receiver, Sender};
+use crate::capture::{self, CaptureRecord, Direction};
+use crate::connection::{ConnectionState, PoolStatuses, ReconnectRequest, Rotation};
+use crate::shutdown::ShutdownSignal;
+use crate::transport::{self, Endpoint};
+use crate::types::{
+    CoinbaseInfo, ConnectionEvent, ConnectionEventKind, DifficultyUpdate, ExtranonceUpdate,
+    JobUpdate, Pool, TlsUpdate, Update,
+};
+use crate::utils::{self, BIP320_VERSION_ROLLING_MASK};
+use async_channel::{unbounded, Receiver, RecvError, Sender};
 use async_std::net::Shutdown;
 use async_std::{
-    io::BufReader,
-    net::TcpStream,
+    future,
+    io::{BufReader, Write},
     prelude::*,
-    sync::{Arc, Mutex},
     task,
 };
 use chrono::prelude::*;
-use chrono::TimeDelta;
+use futures::future::{select, Either};
 use log::warn;
 use log::{debug, error, info};
-use std::net::ToSocketAddrs;
+use std::collections::BTreeMap;
+use std::io;
+use std::pin::pin;
 use std::time::{Duration, Instant};
 use sv1_api::{
     client_to_server,
@@ -23,11 +31,31 @@ use sv1_api::{
     ClientStatus, IsClient,
 };
 
-const USER_AGENT: &str = "stratum-observer";
+pub(crate) const USER_AGENT: &str = "stratum-observer";
 /// The default no-job timeout in seconds.
-const STRATUM_JOB_TIMEOUT_SECONDS: u64 = 60;
+pub(crate) const STRATUM_JOB_TIMEOUT_SECONDS: u64 = 60;
 /// The default max lifetime of a connection to a stratum server.
-const STRATUM_CONNECTION_MAX_LIFETIME_SECONDS: u32 = 590; // just below 10 minutes as some pools disconnect after 10 minutes
+pub(crate) const STRATUM_CONNECTION_MAX_LIFETIME_SECONDS: u32 = 590; // just below 10 minutes as some pools disconnect after 10 minutes
+/// Seconds to wait for a mining.configure response before continuing without version rolling.
+const CONFIGURE_RESPONSE_TIMEOUT_SECONDS: u64 = 5;
+/// The default BIP310 version-rolling min-bit-count requested in mining.configure.
+const DEFAULT_VERSION_ROLLING_MIN_BIT_COUNT: u32 = 2;
+/// The maximum time in seconds we wait before following a client.reconnect request.
+const RECONNECT_MAX_WAIT_SECONDS: u64 = 300;
+
+/// Writes and flushes a message. Flushing is required for TLS connections.
+async fn write_message<W: Write + Unpin>(writer: &mut W, message: &str) -> std::io::Result<()> {
+    writer.write_all(message.as_bytes()).await?;
+    writer.flush().await
+}
+
+/// Sends the connection event to the update channel.
+fn send_event(update_sender: &Sender<Update<'_>>, event: ConnectionEvent) {
+    let pool = event.pool.name.clone();
+    if let Err(e) = update_sender.try_send(Update::Connection(event)) {
+        error!("Failed to send ConnectionEvent for {}: {}", pool, e);
+    }
+}
 
 fn extranonce_from_hex<'a>(hex: &str) -> Extranonce<'a> {
     let data = utils::decode_hex(hex).unwrap();
@@ -36,110 +64,192 @@ fn extranonce_from_hex<'a>(hex: &str) -> Extranonce<'a> {
 
 pub struct Client<'a> {
     pool: Pool,
-    job_sender: Sender<JobUpdate<'a>>,
+    endpoint: String,
+    update_sender: Sender<Update<'a>>,
+    statuses: PoolStatuses,
     message_id: u64,
     time_connected: DateTime<Utc>,
+    /// Receive time of the message currently being handled.
+    time_received: DateTime<Utc>,
     time_last_notify: Option<Instant>,
     extranonce1: Extranonce<'a>,
     extranonce2_size: usize,
+    difficulty: Option<f64>,
     version_rolling_mask: Option<HexU32Be>,
     version_rolling_min_bit: Option<HexU32Be>,
+    configure_request: Option<(u64, Instant)>, // (id, time sent)
+    subscribe_request: Option<u64>,
+    /// Send time of the requests we haven't received a response to yet, by id.
+    request_times: BTreeMap<u64, DateTime<Utc>>,
+    /// Time from sending mining.subscribe to receiving the response.
+    subscribe_latency: Option<Duration>,
     status: ClientStatus,
     last_notify: Option<server_to_client::Notify<'a>>,
     sent_authorize_request: Vec<(u64, String)>, // (id, user_name)
     authorized: Vec<String>,
-    receiver_incoming: Receiver<String>,
     sender_outgoing: Sender<String>,
-    sender_shutdown: Sender<bool>,
     shutting_down: bool,
+    rotation: Rotation,
+    /// The connection reached its max_lifetime and is kept open until its replacement
+    /// received a job.
+    expired: bool,
+    shutdown_signal: ShutdownSignal,
 }
 
-impl<'a> Client<'static> {
-    pub async fn run(pool: &Pool, job_sender: Sender<JobUpdate<'static>>) {
-        // TODO: handle errors
-        let socket = pool.endpoint.to_socket_addrs().unwrap().next().unwrap();
+/// What [Client::process_messages] waits for, besides timers.
+enum Input {
+    /// A line from the pool or an error reading it. Err once the connection is closed.
+    Line(Result<io::Result<(String, DateTime<Utc>)>, RecvError>),
+    /// The replacement of the expired connection received a job.
+    Replaced,
+    /// The pool task stops, as the observer shuts down or the pool was reconfigured.
+    Shutdown,
+}
 
-        let stream = loop {
-            match TcpStream::connect(socket).await {
-                Ok(st) => {
-                    info!("Connected to pool '{}'", pool.name);
-                    break st;
-                }
-                Err(_) => {
-                    warn!("Pool '{}' unreachable...", pool.name);
-                    task::sleep(Duration::from_secs(2)).await;
-                    // TODO: backoff
-                    continue;
-                }
+impl Client<'static> {
+    pub async fn run(
+        pool: &Pool,
+        endpoint: &str,
+        update_sender: Sender<Update<'static>>,
+        statuses: PoolStatuses,
+        capture: Option<Sender<CaptureRecord>>,
+        rotation: Rotation,
+        shutdown_signal: ShutdownSignal,
+    ) {
+        let endpoint = match Endpoint::parse(endpoint) {
+            Ok(endpoint) => endpoint,
+            Err(e) => {
+                error!("Can't connect to pool '{}': {}", pool.name, e);
+                return;
             }
         };
-
-        let arc_stream = Arc::new(stream);
-
-        let (reader, writer) = (arc_stream.clone(), arc_stream.clone());
-        let (sender_incoming, receiver_incoming) = bounded(10);
-        let (sender_outgoing, receiver_outgoing) = bounded(10);
-        let (sender_shutdown, receiver_shutdown) = bounded(1);
-
-        let client = Client {
-            pool: pool.clone(),
-            message_id: 0,
-            job_sender,
-            time_last_notify: None,
-            time_connected: Utc::now(),
-            extranonce1: extranonce_from_hex("00000000"),
-            extranonce2_size: 2,
-            version_rolling_mask: None,
-            version_rolling_min_bit: None,
-            status: ClientStatus::Init,
-            last_notify: None,
-            sent_authorize_request: vec![],
-            authorized: vec![],
-            receiver_incoming,
-            sender_outgoing,
-            sender_shutdown,
-            shutting_down: false,
+        let event = ConnectionEvent::new(
+            pool,
+            &endpoint.to_string(),
+            ConnectionEventKind::ConnectAttempt,
+        );
+        send_event(&update_sender, event);
+        let connect_start = Instant::now();
+        let connection = match shutdown_signal
+            .unless_triggered(transport::connect(pool, &endpoint))
+            .await
+        {
+            None => {
+                debug!("Stopped connecting to {} as its pool task stops", pool.name);
+                let event = ConnectionEvent::new(
+                    pool,
+                    &endpoint.to_string(),
+                    ConnectionEventKind::Shutdown,
+                );
+                send_event(&update_sender, event);
+                return;
+            }
+            Some(Ok(c)) => {
+                info!(
+                    "Connected to pool '{}' at {} ({})",
+                    pool.name,
+                    endpoint,
+                    c.address
+                        .map(|a| a.ip().to_string())
+                        .unwrap_or_else(|| String::from("via proxy"))
+                );
+                c
+            }
+            Some(Err(e)) => {
+                warn!("Pool '{}' unreachable: {}", pool.name, e);
+                let event = ConnectionEvent::failed(pool, &endpoint.to_string(), &e);
+                send_event(&update_sender, event);
+                return;
+            }
+        };
+        let event = ConnectionEvent {
+            address: connection.address,
+            latency: Some(connect_start.elapsed()),
+            ..ConnectionEvent::new(pool, &endpoint.to_string(), ConnectionEventKind::Connected)
         };
+        send_event(&update_sender, event);
+        statuses.set_state(&pool.name, ConnectionState::Handshaking);
+
+        if let Some(tls) = connection.tls {
+            info!(
+                "Negotiated {} with {} to pool '{}': certificate fingerprint {}",
+                tls.protocol_version, tls.cipher_suite, pool.name, tls.certificate_fingerprint
+            );
+            let tls_update = TlsUpdate {
+                timestamp: Utc::now(),
+                pool: pool.clone(),
+                tls,
+            };
+            if let Err(e) = update_sender.try_send(Update::Tls(tls_update)) {
+                error!("Failed to send TlsUpdate for {}: {}", pool.name, e);
+            }
+        }
 
-        let client = Arc::new(Mutex::new(client));
+        let tcp_stream = connection.tcp;
+        let connection_id = capture::new_connection_id();
+        let (reader, mut writer) = futures::AsyncReadExt::split(connection.stream);
+        let (sender_incoming, receiver_incoming) = unbounded();
+        let (sender_outgoing, receiver_outgoing) = unbounded::<String>();
 
-        // Inbound message receive task
-        let cloned = client.clone();
+        // Inbound message receive task. Lines are timestamped as soon as they are read. A read
+        // error is passed on, and the channel is closed when the stream is closed.
+        let inbound_pool_name = pool.name.clone();
+        let inbound_capture = capture
+            .clone()
+            .map(|c| (c, endpoint.to_string(), connection_id.clone()));
         task::spawn(async move {
-            let mut messages = BufReader::new(&*reader).lines();
+            let mut messages = BufReader::new(reader).lines();
             while let Some(message) = messages.next().await {
                 match message {
                     Ok(msg) => {
-                        if let Err(e) = sender_incoming.send(msg).await {
-                            debug!(
-                                "Could not send received message into processing channel: {}",
-                                e
+                        let received = Utc::now();
+                        if let Some((capture, endpoint, connection_id)) = &inbound_capture {
+                            let record = CaptureRecord::new(
+                                &inbound_pool_name,
+                                endpoint,
+                                connection_id,
+                                Direction::In,
+                                &msg,
+                                received,
                             );
+                            let _ = capture.try_send(record);
+                        }
+                        if sender_incoming.send(Ok((msg, received))).await.is_err() {
+                            // the client stopped processing messages
                             break;
                         }
                     }
                     Err(e) => {
-                        warn!("could not read incoming message: {}", e);
+                        let _ = sender_incoming.send(Err(e)).await;
                         break;
                     }
                 }
             }
-            if let Some(mut self_) = cloned.try_lock() {
-                debug!("Stream with '{}' closed", self_.pool.name);
-                self_.shutdown().await;
-            }
+            debug!("Stream with '{}' closed", inbound_pool_name);
         });
 
         // Outbound message send task
         let outbound_pool_clone = pool.clone();
+        let outbound_capture = capture.map(|c| (c, endpoint.to_string(), connection_id));
         task::spawn(async move {
             loop {
                 match receiver_outgoing.recv().await {
                     Ok(message) => {
-                        if let Err(e) = (&*writer).write_all(message.as_bytes()).await {
+                        if let Err(e) = write_message(&mut writer, &message).await {
                             debug!("Failed to send stratum message to '{}'. Stopping outbound message send task: {}", outbound_pool_clone.name, e);
                             break;
                         };
+                        if let Some((capture, endpoint, connection_id)) = &outbound_capture {
+                            let record = CaptureRecord::new(
+                                &outbound_pool_clone.name,
+                                endpoint,
+                                connection_id,
+                                Direction::Out,
+                                &message,
+                                Utc::now(),
+                            );
+                            let _ = capture.try_send(record);
+                        }
                     }
                     Err(e) => {
                         debug!("Outbound message receiver to '{}' is closed. Stopping outbound message send task: {}", outbound_pool_clone.name, e);
@@ -153,130 +263,496 @@ impl<'a> Client<'static> {
             );
         });
 
-        // Message parsing and processing task
-        let cloned = client.clone();
-        task::spawn(async move {
-            loop {
-                if let Some(mut self_) = cloned.try_lock() {
-                    let incoming = self_.receiver_incoming.try_recv();
-                    self_.parse_message(incoming).await;
-
-                    // check connection age, disconnect if connection too old
-                    let duration_connected = Utc::now() - self_.time_connected;
-                    let max_lifetime: u32 = self_
-                        .pool
-                        .max_lifetime
-                        .unwrap_or(STRATUM_CONNECTION_MAX_LIFETIME_SECONDS);
-                    if duration_connected > TimeDelta::seconds(max_lifetime.into()) {
+        let mut client = Client::new(
+            pool,
+            endpoint.to_string(),
+            update_sender,
+            statuses,
+            sender_outgoing,
+        );
+        client.rotation = rotation;
+        client.shutdown_signal = shutdown_signal;
+        client.process_messages(receiver_incoming).await;
+        client.shutdown();
+        // Try to shut down the stream. On errors, do nothing - just terminate this client (and open a new one).
+        let _ = tcp_stream.shutdown(Shutdown::Both);
+    }
+
+    /// Handles incoming messages and timers as they happen until the connection is closed,
+    /// too old, no new job has been received in a while, or the pool task stops. A
+    /// connection that reached its max_lifetime is kept open until its replacement received
+    /// a job, if it's rotated.
+    async fn process_messages(
+        &mut self,
+        receiver_incoming: Receiver<io::Result<(String, DateTime<Utc>)>>,
+    ) {
+        let max_lifetime = self.pool.max_lifetime();
+        let job_timeout = self.pool.job_timeout();
+        // an expired connection is kept open for at most another job_timeout
+        let expired_lifetime = max_lifetime.saturating_add(job_timeout);
+        let connected = Instant::now();
+
+        self.advance_handshake().await;
+        while !self.shutting_down {
+            let until_max_lifetime = if self.expired {
+                expired_lifetime.saturating_sub(connected.elapsed())
+            } else {
+                max_lifetime.saturating_sub(connected.elapsed())
+            };
+            let until_job_timeout =
+                job_timeout.saturating_sub(self.time_last_notify.unwrap_or(connected).elapsed());
+            let until_configure_timeout = match (&self.status, self.configure_request) {
+                (ClientStatus::Init, Some((_, sent))) => {
+                    Duration::from_secs(CONFIGURE_RESPONSE_TIMEOUT_SECONDS)
+                        .saturating_sub(sent.elapsed())
+                }
+                _ => Duration::MAX,
+            };
+            let timeout = until_max_lifetime
+                .min(until_job_timeout)
+                .min(until_configure_timeout);
+
+            let input = async {
+                let line = pin!(receiver_incoming.recv());
+                let replaced = pin!(async {
+                    if self.expired {
+                        self.rotation.replaced().await
+                    } else {
+                        future::pending().await
+                    }
+                });
+                let shutdown = pin!(self.shutdown_signal.wait());
+                match select(line, select(replaced, shutdown)).await {
+                    Either::Left((line, _)) => Input::Line(line),
+                    Either::Right((Either::Left(_), _)) => Input::Replaced,
+                    Either::Right((Either::Right(_), _)) => Input::Shutdown,
+                }
+            };
+            match future::timeout(timeout, input).await {
+                Ok(Input::Shutdown) => {
+                    debug!(
+                        "Closing connection to {} as its pool task stops",
+                        self.pool.name
+                    );
+                    self.send_connection_event(ConnectionEventKind::Shutdown, None);
+                    return;
+                }
+                Ok(Input::Replaced) => {
+                    debug!(
+                        "Closing connection to {} as its replacement received a job",
+                        self.pool.name
+                    );
+                    self.send_connection_event(
+                        ConnectionEventKind::MaxLifetime,
+                        Some(String::from("replaced")),
+                    );
+                    return;
+                }
+                Ok(Input::Line(Ok(Ok((line, received))))) => {
+                    debug!("recv from {}: {}", self.pool.name, line);
+                    self.handle_line(&line, received);
+                }
+                Ok(Input::Line(Ok(Err(e)))) => {
+                    warn!(
+                        "could not read incoming message from '{}': {}",
+                        self.pool.name, e
+                    );
+                    self.send_connection_event(ConnectionEventKind::ReadError, Some(e.to_string()));
+                    return;
+                }
+                Ok(Input::Line(Err(_))) => {
+                    debug!("Connection to '{}' closed", self.pool.name);
+                    self.send_connection_event(ConnectionEventKind::RemoteClose, None);
+                    return;
+                }
+                Err(_) => {
+                    if self.expired && connected.elapsed() >= expired_lifetime {
                         debug!(
-                            "Closing connection to {} as the connection is {:?} old (max_lifetime={}s)",
-                            self_.pool.name, duration_connected, max_lifetime,
+                            "Closing expired connection to {} as it wasn't replaced within {}s",
+                            self.pool.name,
+                            job_timeout.as_secs(),
                         );
-                        self_.shutdown().await;
-                        break;
+                        self.send_connection_event(
+                            ConnectionEventKind::MaxLifetime,
+                            Some(String::from("not replaced")),
+                        );
+                        return;
                     }
-
-                    // check last job time, disconnect if too old
-                    if let Some(time_last_notify) = self_.time_last_notify {
-                        if time_last_notify.elapsed()
-                            > Duration::from_secs(STRATUM_JOB_TIMEOUT_SECONDS)
-                        {
-                            warn!(
-                                "No notify from {} in more than {}s: disconnecting...",
-                                self_.pool.name, STRATUM_JOB_TIMEOUT_SECONDS
+                    if !self.expired && connected.elapsed() >= max_lifetime {
+                        if self.rotation.expire() {
+                            debug!(
+                                "Connection to {} is {:?} old (max_lifetime={}s): keeping it open until its replacement received a job",
+                                self.pool.name,
+                                connected.elapsed(),
+                                max_lifetime.as_secs(),
                             );
-                            self_.shutdown().await;
-                            break;
+                            self.expired = true;
+                            continue;
                         }
+                        debug!(
+                            "Closing connection to {} as the connection is {:?} old (max_lifetime={}s)",
+                            self.pool.name,
+                            connected.elapsed(),
+                            max_lifetime.as_secs(),
+                        );
+                        self.send_connection_event(ConnectionEventKind::MaxLifetime, None);
+                        return;
                     }
+                    if self.time_last_notify.unwrap_or(connected).elapsed() >= job_timeout {
+                        warn!(
+                            "No notify from {} in more than {}s: disconnecting...",
+                            self.pool.name,
+                            job_timeout.as_secs()
+                        );
+                        self.send_connection_event(ConnectionEventKind::JobTimeout, None);
+                        return;
+                    }
+                    self.check_configure_timeout();
                 }
-                // It's healthy to sleep after giving up the lock so the other thread has a shot
-                // at acquiring it - it also prevents pegging the cpu
-                task::sleep(Duration::from_millis(100)).await;
             }
-        });
+            self.advance_handshake().await;
+        }
+    }
 
-        // initialize client loop
-        loop {
-            let mut client_ = client.lock().await;
-            match client_.status {
-                ClientStatus::Init => client_.send_configure().await,
-                ClientStatus::Configured => client_.send_subscribe().await,
-                ClientStatus::Subscribed => {
-                    client_.send_authorize().await;
-                    break;
+    /// Sends the next handshake request once the pool answered the previous one:
+    /// mining.configure, then mining.subscribe, and finally mining.extranonce.subscribe
+    /// and mining.authorize.
+    async fn advance_handshake(&mut self) {
+        match self.status {
+            ClientStatus::Init => {
+                if self.configure_request.is_none() {
+                    self.send_configure().await;
                 }
             }
-            drop(client_);
-            // If we already know we're shutting down, we can break here
-            // and directly go into the wait-for-shutdown loop.
-            if !receiver_shutdown.is_empty() {
-                warn!(
-                    "Existing shutdown signal during client initialization loop for pool '{}'",
-                    pool.name
-                );
-                break;
+            ClientStatus::Configured => {
+                if self.subscribe_request.is_none() {
+                    self.send_subscribe().await;
+                }
+            }
+            ClientStatus::Subscribed => {
+                if self.sent_authorize_request.is_empty() {
+                    self.send_latency_event(
+                        ConnectionEventKind::Subscribed,
+                        self.subscribe_latency,
+                    );
+                    self.send_extranonce_subscribe().await;
+                    self.send_authorize().await;
+                }
             }
-            task::sleep(Duration::from_secs(1)).await;
         }
+    }
 
-        // Wait-for-shutdown loop
-        loop {
-            match receiver_shutdown.recv().await {
-                Ok(_) => {
-                    // Try to shut down the stream. On errors, do nothing - just terminate this client (and open a new one).
-                    let _ = arc_stream.shutdown(Shutdown::Both);
-                    return;
+    fn new(
+        pool: &Pool,
+        endpoint: String,
+        update_sender: Sender<Update<'static>>,
+        statuses: PoolStatuses,
+        sender_outgoing: Sender<String>,
+    ) -> Client<'static> {
+        Client {
+            pool: pool.clone(),
+            endpoint,
+            message_id: 0,
+            update_sender,
+            statuses,
+            time_last_notify: None,
+            time_connected: Utc::now(),
+            time_received: Utc::now(),
+            extranonce1: extranonce_from_hex("00000000"),
+            extranonce2_size: 2,
+            difficulty: None,
+            version_rolling_mask: None,
+            version_rolling_min_bit: None,
+            configure_request: None,
+            subscribe_request: None,
+            request_times: BTreeMap::new(),
+            subscribe_latency: None,
+            status: ClientStatus::Init,
+            last_notify: None,
+            sent_authorize_request: vec![],
+            authorized: vec![],
+            sender_outgoing,
+            shutting_down: false,
+            rotation: Rotation::default(),
+            expired: false,
+            shutdown_signal: ShutdownSignal::default(),
+        }
+    }
+
+    /// A client for replaying a captured connection opened at `time_connected`. It doesn't
+    /// send requests: they are taken from the capture with [Client::replay_line] instead.
+    pub(crate) fn for_replay(
+        pool: &Pool,
+        endpoint: String,
+        update_sender: Sender<Update<'static>>,
+        time_connected: DateTime<Utc>,
+    ) -> Client<'static> {
+        let (sender_outgoing, _) = unbounded();
+        let mut client = Client::new(
+            pool,
+            endpoint,
+            update_sender,
+            PoolStatuses::default(),
+            sender_outgoing,
+        );
+        client.time_connected = time_connected;
+        client
+    }
+
+    /// Replays a captured line: received lines are handled as if they were just received,
+    /// and sent requests are tracked to be able to match the pool's responses.
+    pub(crate) fn replay_line(
+        &mut self,
+        direction: Direction,
+        line: &str,
+        timestamp: DateTime<Utc>,
+    ) {
+        match direction {
+            Direction::In => self.handle_line(line, timestamp),
+            Direction::Out => self.track_request(line, timestamp),
+        }
+    }
+
+    fn track_request(&mut self, line: &str, sent: DateTime<Utc>) {
+        let request: serde_json::Value = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("could not parse captured request: {} - msg={}", e, line);
+                return;
+            }
+        };
+        let Some(id) = request["id"].as_u64() else {
+            return;
+        };
+        match request["method"].as_str() {
+            Some("mining.configure") => self.configure_request = Some((id, Instant::now())),
+            Some("mining.subscribe") => {
+                // we continued without version rolling
+                if let ClientStatus::Init = self.status {
+                    self.status = ClientStatus::Configured;
                 }
-                Err(e) => {
-                    error!("Shutdown receiver closed before receiving shutdown: {}", e);
-                    return;
+                self.subscribe_request = Some(id);
+            }
+            Some("mining.authorize") => {
+                let name = request["params"][0].as_str().unwrap_or_default();
+                self.sent_authorize_request.push((id, name.to_string()));
+            }
+            _ => (),
+        }
+        self.request_times.insert(id, sent);
+        self.message_id = id + 1;
+    }
+
+    /// Handles a line received from the pool at the given time.
+    fn handle_line(&mut self, line: &str, received: DateTime<Utc>) {
+        self.time_received = received;
+        let value: serde_json::Value = match serde_json::from_str(line) {
+            Ok(value) => value,
+            Err(e) => {
+                warn!("could not parse message: {} - msg={}", e, line);
+                return;
+            }
+        };
+        let latency = value["id"]
+            .as_u64()
+            .and_then(|id| self.request_times.remove(&id))
+            .and_then(|sent| (received - sent).to_std().ok());
+        if value["id"].is_u64() && value["id"].as_u64() == self.subscribe_request {
+            self.subscribe_latency = latency;
+        }
+
+        // The mining.configure response and mining.set_version_mask can't be (fully)
+        // parsed by sv1_api, so we handle them here.
+        if value["id"].is_u64() && value["id"].as_u64() == self.configure_request.map(|r| r.0) {
+            self.handle_configure_response(&value);
+            return;
+        }
+        if value["method"] == "mining.set_version_mask" {
+            self.handle_set_version_mask_params(&value["params"]);
+            return;
+        }
+        // client.reconnect and client.show_message aren't handled by sv1_api.
+        if value["method"] == "client.reconnect" {
+            self.handle_reconnect_params(&value["params"]);
+            return;
+        }
+        if value["method"] == "client.show_message" {
+            self.handle_show_message_params(&value["params"]);
+            return;
+        }
+        if value["method"] == "mining.notify" {
+            self.statuses.notified(&self.pool.name, line, received);
+        }
+        // sv1_api can't parse error responses with an error array, as sent by most pools.
+        if let Some(name) = value["id"]
+            .as_u64()
+            .and_then(|id| self.id_is_authorize(&id))
+        {
+            self.handle_authorize_response(&value, name, latency);
+            return;
+        }
+
+        match serde_json::from_value(value) {
+            Ok(msg) => {
+                if let Err(e) = self.handle_message(msg) {
+                    warn!(
+                        "could not handle '{}' message (ignoring): {}",
+                        self.pool.name, e
+                    );
                 }
             }
+            Err(e) => {
+                warn!("could not parse message: {} - msg={}", e, line);
+            }
         }
     }
 
-    async fn parse_message(
+    fn handle_configure_response(&mut self, response: &serde_json::Value) {
+        let result = &response["result"];
+        let mask = match result["version-rolling"].as_bool() {
+            Some(true) => result["version-rolling.mask"]
+                .as_str()
+                .and_then(utils::parse_version_mask),
+            _ => None,
+        };
+        match mask {
+            Some(mask) => info!(
+                "Pool '{}' allows version rolling with mask {:08x}",
+                self.pool.name, mask
+            ),
+            None => info!(
+                "Pool '{}' doesn't allow version rolling: {}",
+                self.pool.name, response
+            ),
+        }
+        self.version_rolling_mask = mask.map(HexU32Be);
+        if let ClientStatus::Init = self.status {
+            self.status = ClientStatus::Configured;
+        }
+    }
+
+    fn handle_authorize_response(
         &mut self,
-        incoming_message: Result<String, async_channel::TryRecvError>,
+        response: &serde_json::Value,
+        name: String,
+        latency: Option<Duration>,
     ) {
-        if let Ok(line) = incoming_message {
-            debug!("recv from {}: {}", self.pool.name, line);
-            match serde_json::from_str(&line) {
-                Ok(msg) => {
-                    if let Err(e) = self.handle_message(msg) {
-                        warn!(
-                            "could not handle '{}' message (ignoring): {}",
-                            self.pool.name, e
-                        );
-                    }
-                }
-                Err(e) => {
-                    warn!("could not parse message: {} - msg={}", e, line);
-                }
+        if response["result"] == true {
+            info!("Pool '{}' authorized user '{}'", self.pool.name, name);
+            self.authorize_user_name(name);
+            self.send_latency_event(ConnectionEventKind::Authorized, latency);
+        } else {
+            // Without an authorized user, the pool might still send jobs, but they might not
+            // be what miners get. Close the connection and retry later.
+            warn!(
+                "Pool '{}' rejected user '{}': {}",
+                self.pool.name, name, response["error"]
+            );
+            let message = response["error"].to_string();
+            self.send_connection_event(ConnectionEventKind::Rejected, Some(message.clone()));
+            self.statuses.rejected(&self.pool.name, message);
+            self.shutdown();
+        }
+    }
+
+    fn handle_set_version_mask_params(&mut self, params: &serde_json::Value) {
+        match params[0].as_str().and_then(utils::parse_version_mask) {
+            Some(mask) => {
+                info!(
+                    "Pool '{}' changed the version rolling mask from {:08x?} to {:08x}",
+                    self.pool.name,
+                    self.version_rolling_mask.as_ref().map(|m| m.0),
+                    mask
+                );
+                self.version_rolling_mask = Some(HexU32Be(mask));
             }
+            None => warn!(
+                "could not parse mining.set_version_mask from '{}': params={}",
+                self.pool.name, params
+            ),
         }
     }
 
+    /// Follows a client.reconnect request if the pool's reconnect policy allows it. The
+    /// optional params are the host, the port and the seconds to wait before reconnecting.
+    /// Missing values default to the current endpoint's.
+    fn handle_reconnect_params(&mut self, params: &serde_json::Value) {
+        let current = match Endpoint::parse(&self.endpoint) {
+            Ok(endpoint) => endpoint,
+            Err(e) => {
+                warn!("Ignoring client.reconnect from '{}': {}", self.pool.name, e);
+                return;
+            }
+        };
+        // Some pools send the port and wait time as strings.
+        let number = |v: &serde_json::Value| {
+            v.as_u64()
+                .or_else(|| v.as_str().and_then(|s| s.parse().ok()))
+        };
+        let target = Endpoint {
+            host: params[0]
+                .as_str()
+                .filter(|host| !host.is_empty())
+                .map(String::from)
+                .unwrap_or_else(|| current.host.clone()),
+            port: number(&params[1])
+                .and_then(|port| u16::try_from(port).ok())
+                .unwrap_or(current.port),
+            ..current.clone()
+        };
+        let wait = number(&params[2])
+            .unwrap_or_default()
+            .min(RECONNECT_MAX_WAIT_SECONDS);
+        let message = format!("{} wait={}s", target, wait);
+
+        if !self.pool.reconnect_policy.allows(&current, &target) {
+            warn!(
+                "Pool '{}' asked us to reconnect to {}: refused by the reconnect policy {:?}",
+                self.pool.name, target, self.pool.reconnect_policy
+            );
+            self.send_connection_event(ConnectionEventKind::ReconnectRefused, Some(message));
+            return;
+        }
+        info!(
+            "Pool '{}' asked us to reconnect to {} in {}s",
+            self.pool.name, target, wait
+        );
+        self.send_connection_event(ConnectionEventKind::Reconnect, Some(message));
+        self.statuses.reconnect_requested(
+            &self.pool.name,
+            ReconnectRequest {
+                endpoint: (target != current).then(|| target.to_string()),
+                wait: Duration::from_secs(wait),
+            },
+        );
+        self.shutdown();
+    }
+
+    fn handle_show_message_params(&mut self, params: &serde_json::Value) {
+        let message = match params[0].as_str() {
+            Some(message) => message.to_string(),
+            None => params.to_string(),
+        };
+        info!("Pool '{}' says: {}", self.pool.name, message);
+        self.send_connection_event(ConnectionEventKind::PoolMessage, Some(message));
+    }
+
     async fn send_message(&mut self, msg: &json_rpc::Message) {
         let content =
             serde_json::to_string(&msg).expect("could not serialize message as JSON string");
         debug!("sending to {}: {}", self.pool.name, content);
+        self.request_times.insert(self.message_id, Utc::now());
         if let Err(e) = self.sender_outgoing.send(format!("{}\n", content)).await {
             warn!("could not send message to '{}': {}", self.pool.name, e);
-            self.shutdown().await;
+            self.shutdown();
         } else {
             self.message_id += 1;
         }
     }
 
     pub async fn send_subscribe(&mut self) {
-        loop {
-            if let ClientStatus::Configured = self.status {
-                break;
-            }
-        }
         let subscribe = self.subscribe(self.message_id, None).unwrap();
+        self.subscribe_request = Some(self.message_id);
         self.send_message(&subscribe).await;
     }
 
@@ -290,41 +766,152 @@ impl<'a> Client<'static> {
         }
     }
 
+    /// Signals the pool that we support mining.set_extranonce messages (see BIP310).
+    pub async fn send_extranonce_subscribe(&mut self) {
+        let extranonce_subscribe: json_rpc::Message = json_rpc::StandardRequest {
+            id: self.message_id,
+            method: String::from("mining.extranonce.subscribe"),
+            params: serde_json::Value::Array(vec![]),
+        }
+        .into();
+        self.send_message(&extranonce_subscribe).await;
+    }
+
+    /// Requests BIP310 version rolling. The request is built here as sv1_api encodes the
+    /// min-bit-count as hex string while BIP310 specifies an integer.
     pub async fn send_configure(&mut self) {
-        let configure = self.configure(self.message_id);
+        let mask = self
+            .pool
+            .version_rolling_mask
+            .as_deref()
+            .and_then(utils::parse_version_mask)
+            .unwrap_or(BIP320_VERSION_ROLLING_MASK);
+        let min_bit_count = self
+            .pool
+            .version_rolling_min_bit_count
+            .unwrap_or(DEFAULT_VERSION_ROLLING_MIN_BIT_COUNT);
+        let params = if mask == 0 {
+            serde_json::json!([[], {}])
+        } else {
+            serde_json::json!([["version-rolling"], {
+                "version-rolling.mask": format!("{:08x}", mask),
+                "version-rolling.min-bit-count": min_bit_count,
+            }])
+        };
+        let configure: json_rpc::Message = json_rpc::StandardRequest {
+            id: self.message_id,
+            method: String::from("mining.configure"),
+            params,
+        }
+        .into();
+        self.configure_request = Some((self.message_id, Instant::now()));
         self.send_message(&configure).await;
-        // since we currently don't need to configure anything, treat
-        // having send the configure message as being configured.
-        // This allows us to support pools that don't respond to the (optional)
-        // configure message.
-        self.status = ClientStatus::Configured;
-    }
-
-    pub async fn shutdown(&mut self) {
-        if !self.shutting_down {
-            self.sender_outgoing.close();
-            self.receiver_incoming.close();
-            self.sender_shutdown
-                .send(true)
-                .await
-                .expect("Could not send shutdown");
-            self.shutting_down = true;
+    }
+
+    /// Continues without version rolling if the pool didn't respond to the (optional)
+    /// mining.configure in time. Some pools don't respond to it at all.
+    pub fn check_configure_timeout(&mut self) {
+        if let (ClientStatus::Init, Some((_, sent))) = (&self.status, self.configure_request) {
+            if sent.elapsed() > Duration::from_secs(CONFIGURE_RESPONSE_TIMEOUT_SECONDS) {
+                info!(
+                    "No mining.configure response from '{}' within {}s: continuing without version rolling",
+                    self.pool.name, CONFIGURE_RESPONSE_TIMEOUT_SECONDS
+                );
+                self.status = ClientStatus::Configured;
+            }
         }
     }
+
+    /// Stops processing messages and closes the outbound message send task.
+    pub fn shutdown(&mut self) {
+        self.sender_outgoing.close();
+        self.shutting_down = true;
+    }
+}
+
+impl<'a> Client<'a> {
+    fn send_event(&self, event: ConnectionEvent) {
+        send_event(&self.update_sender, event);
+    }
+
+    fn send_connection_event(&self, kind: ConnectionEventKind, message: Option<String>) {
+        self.send_event(ConnectionEvent {
+            message,
+            ..ConnectionEvent::new(&self.pool, &self.endpoint, kind)
+        });
+    }
+
+    /// Sends a connection event for a handshake step that took `latency`.
+    fn send_latency_event(&self, kind: ConnectionEventKind, latency: Option<Duration>) {
+        self.send_event(ConnectionEvent {
+            latency,
+            ..ConnectionEvent::new(&self.pool, &self.endpoint, kind)
+        });
+    }
 }
 
 impl<'a> IsClient<'a> for Client<'a> {
     fn handle_set_difficulty(
         &mut self,
-        _conf: &mut server_to_client::SetDifficulty,
+        set_difficulty: &mut server_to_client::SetDifficulty,
     ) -> Result<(), Error<'a>> {
+        if self.difficulty != Some(set_difficulty.value) {
+            debug!(
+                "Pool '{}' changed difficulty from {:?} to {}",
+                self.pool.name, self.difficulty, set_difficulty.value
+            );
+            let difficulty_update = DifficultyUpdate {
+                timestamp: self.time_received,
+                pool: self.pool.clone(),
+                difficulty: set_difficulty.value,
+                previous_difficulty: self.difficulty,
+            };
+            if let Err(e) = self
+                .update_sender
+                .try_send(Update::Difficulty(difficulty_update))
+            {
+                error!(
+                    "Failed to send DifficultyUpdate for {}: {}",
+                    self.pool.name, e
+                );
+            }
+        }
+        self.difficulty = Some(set_difficulty.value);
         Ok(())
     }
 
     fn handle_set_extranonce(
         &mut self,
-        _conf: &mut server_to_client::SetExtranonce,
+        set_extranonce: &mut server_to_client::SetExtranonce,
     ) -> Result<(), Error<'a>> {
+        let extranonce1 = Extranonce::try_from(set_extranonce.extra_nonce1.as_ref().to_vec())?;
+        info!(
+            "Pool '{}' changed extranonce1 from {} to {} and extranonce2_size from {} to {}",
+            self.pool.name,
+            utils::encode_hex(self.extranonce1.as_ref()),
+            utils::encode_hex(extranonce1.as_ref()),
+            self.extranonce2_size,
+            set_extranonce.extra_nonce2_size,
+        );
+        let extranonce_update = ExtranonceUpdate {
+            timestamp: self.time_received,
+            pool: self.pool.clone(),
+            extranonce1: extranonce1.as_ref().to_vec(),
+            extranonce2_size: set_extranonce.extra_nonce2_size,
+            previous_extranonce1: self.extranonce1.as_ref().to_vec(),
+            previous_extranonce2_size: self.extranonce2_size,
+        };
+        self.extranonce1 = extranonce1;
+        self.extranonce2_size = set_extranonce.extra_nonce2_size;
+        if let Err(e) = self
+            .update_sender
+            .try_send(Update::Extranonce(extranonce_update))
+        {
+            error!(
+                "Failed to send ExtranonceUpdate for {}: {}",
+                self.pool.name, e
+            );
+        }
         Ok(())
     }
 
@@ -332,21 +919,56 @@ impl<'a> IsClient<'a> for Client<'a> {
         &mut self,
         _conf: &mut server_to_client::SetVersionMask,
     ) -> Result<(), Error<'a>> {
+        // sv1_api doesn't expose the mask: mining.set_version_mask is handled
+        // in handle_line() instead.
         Ok(())
     }
 
     fn handle_notify(&mut self, notify: server_to_client::Notify<'a>) -> Result<(), Error<'a>> {
+        let first_job = self.time_last_notify.is_none();
+        if first_job {
+            self.send_latency_event(
+                ConnectionEventKind::FirstJob,
+                (self.time_received - self.time_connected).to_std().ok(),
+            );
+        }
         self.time_last_notify = Some(Instant::now());
+        // the replacement of an expired connection reports the pool's state
+        if !self.expired || self.rotation.is_current() {
+            self.statuses
+                .set_state(&self.pool.name, ConnectionState::Live);
+        }
         self.last_notify = Some(notify.clone());
         let job_update = JobUpdate {
-            timestamp: Utc::now(),
+            timestamp: self.time_received,
             pool: self.pool.clone(),
+            endpoint: self.endpoint.clone(),
             job: notify.clone(),
             extranonce1: self.extranonce1.clone(),
             extranonce2_size: self.extranonce2_size,
+            difficulty: self.difficulty,
+            version_rolling_mask: self.version_rolling_mask.as_ref().map(|m| m.0),
             time_connected: self.time_connected,
+            coinbase_info: Box::new(CoinbaseInfo::decode(
+                &notify,
+                &self.extranonce1,
+                self.extranonce2_size,
+                self.pool.network(),
+            )),
+            identification: None,
+            witness_commitment_changed: false,
         };
-        if let Err(e) = self.job_sender.try_send(job_update) {
+        if !self
+            .rotation
+            .publish(job_update.template_fingerprint(), first_job, self.expired)
+        {
+            debug!(
+                "Suppressing job {} from '{}': it duplicates the last job of the overlapping connection",
+                notify.job_id, self.pool.name
+            );
+            return Ok(());
+        }
+        if let Err(e) = self.update_sender.try_send(Update::Job(job_update)) {
             error!("Failed to send JobUpdate for {}: {}", self.pool.name, e);
         }
         Ok(())
@@ -399,7 +1021,7 @@ impl<'a> IsClient<'a> for Client<'a> {
     }
 
     fn signature(&self) -> String {
-        format!("{}", USER_AGENT)
+        self.pool.user_agent()
     }
 
     fn status(&self) -> ClientStatus {
@@ -439,7 +1061,7 @@ impl<'a> IsClient<'a> for Client<'a> {
         id: u64,
         name: String,
         password: String,
-    ) -> Result<json_rpc::Message, Error> {
+    ) -> Result<json_rpc::Message, Error<'_>> {
         match self.status() {
             ClientStatus::Init => Err(Error::IncorrectClientStatus("mining.authorize".to_string())),
             _ => {
@@ -449,7 +1071,7 @@ impl<'a> IsClient<'a> for Client<'a> {
         }
     }
 
-    fn last_notify(&self) -> Option<server_to_client::Notify> {
+    fn last_notify(&self) -> Option<server_to_client::Notify<'_>> {
         self.last_notify.clone()
     }
 
@@ -461,3 +1083,779 @@ impl<'a> IsClient<'a> for Client<'a> {
         Ok(None)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::{ReconnectPolicy, Rotator};
+    use crate::mock_pool::{MockPool, EXTRANONCE1, EXTRANONCE2_SIZE};
+    use crate::types::OutputType;
+    use async_std::net::TcpListener;
+    use chrono::TimeDelta;
+    use serde_json::json;
+
+    const SUBSCRIBE_RESPONSE: &str = r#"{"id":1,"result":[[["mining.notify","ae6812eb4cd7735a302a8a9dd95cf71f"]],"08000002",4],"error":null}"#;
+    const NOTIFY: &str = r#"{"params":["bf","4d16b6f85af6e2198f44ae2a6de67f78487ae5611b77c6c0440b921e00000000","01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff20020862062f503253482f04b8864e5008","072f736c7573682f000000000100f2052a010000001976a914d23fcdf86f7e756a64a7a9688ef9903327048ed988ac00000000",[],"00000002","1c2ac4af","504e86b9",false],"id":null,"method":"mining.notify"}"#;
+
+    fn test_client() -> (Client<'static>, Receiver<Update<'static>>) {
+        let pool = Pool::test(["127.0.0.1:3333"]);
+        let (update_sender, update_receiver) = unbounded();
+        let (sender_outgoing, _) = unbounded();
+        let client = Client::new(
+            &pool,
+            pool.endpoints[0].clone(),
+            update_sender,
+            PoolStatuses::default(),
+            sender_outgoing,
+        );
+        (client, update_receiver)
+    }
+
+    fn handle(client: &mut Client<'static>, line: &str) {
+        client.handle_line(line, Utc::now());
+    }
+
+    /// Receives the next update that isn't a connection event.
+    fn try_recv_update(updates: &Receiver<Update<'static>>) -> Update<'static> {
+        loop {
+            match updates.try_recv().unwrap() {
+                Update::Connection(_) => continue,
+                u => return u,
+            }
+        }
+    }
+
+    #[async_std::test]
+    async fn process_messages_as_they_arrive() {
+        let pool = Pool::test(["127.0.0.1:3333"]);
+        let (update_sender, updates) = unbounded();
+        let (sender_outgoing, outgoing) = unbounded();
+        let (sender_incoming, receiver_incoming) = unbounded();
+        let mut client = Client::new(
+            &pool,
+            pool.endpoints[0].clone(),
+            update_sender,
+            PoolStatuses::default(),
+            sender_outgoing,
+        );
+
+        let received = Utc::now() - TimeDelta::milliseconds(250);
+        for line in [
+            r#"{"id":0,"result":{"version-rolling":false},"error":null}"#,
+            SUBSCRIBE_RESPONSE,
+            NOTIFY,
+        ] {
+            sender_incoming
+                .send(Ok((line.to_string(), received)))
+                .await
+                .unwrap();
+        }
+        sender_incoming.close();
+        future::timeout(
+            Duration::from_secs(1),
+            client.process_messages(receiver_incoming),
+        )
+        .await
+        .expect("should stop processing once the connection is closed");
+
+        let mut methods = vec![];
+        while let Ok(message) = outgoing.try_recv() {
+            let message: serde_json::Value = serde_json::from_str(&message).unwrap();
+            methods.push(message["method"].as_str().unwrap().to_string());
+        }
+        assert_eq!(
+            methods,
+            vec![
+                "mining.configure",
+                "mining.subscribe",
+                "mining.extranonce.subscribe",
+                "mining.authorize",
+            ]
+        );
+        let mut events = vec![];
+        let mut jobs = vec![];
+        while let Ok(update) = updates.try_recv() {
+            match update {
+                Update::Connection(event) => events.push(event.kind),
+                Update::Job(job) => jobs.push(job),
+                u => panic!("unexpected update {:?}", u),
+            }
+        }
+        assert_eq!(
+            events,
+            vec![
+                ConnectionEventKind::Subscribed,
+                ConnectionEventKind::FirstJob,
+                ConnectionEventKind::RemoteClose
+            ]
+        );
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].timestamp, received);
+    }
+
+    #[test]
+    fn measure_handshake_latencies() {
+        let pool = Pool::test(["127.0.0.1:3333"]);
+        let (update_sender, updates) = unbounded();
+        let connected = Utc::now();
+        let mut client =
+            Client::for_replay(&pool, pool.endpoints[0].clone(), update_sender, connected);
+        let at = |millis| connected + TimeDelta::milliseconds(millis);
+        client.replay_line(
+            Direction::Out,
+            r#"{"id":1,"method":"mining.subscribe","params":[]}"#,
+            at(10),
+        );
+        client.replay_line(Direction::In, SUBSCRIBE_RESPONSE, at(130));
+        assert_eq!(client.subscribe_latency, Some(Duration::from_millis(120)));
+
+        client.replay_line(
+            Direction::Out,
+            r#"{"id":2,"method":"mining.authorize","params":["observer",""]}"#,
+            at(200),
+        );
+        client.replay_line(
+            Direction::In,
+            r#"{"id":2,"result":true,"error":null}"#,
+            at(280),
+        );
+        client.replay_line(Direction::In, NOTIFY, at(2000));
+        client.replay_line(Direction::In, NOTIFY, at(3000));
+
+        let events: Vec<_> = std::iter::from_fn(|| updates.try_recv().ok())
+            .filter_map(|u| match u {
+                Update::Connection(event) => Some((event.kind, event.latency)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (
+                    ConnectionEventKind::Authorized,
+                    Some(Duration::from_millis(80))
+                ),
+                (ConnectionEventKind::FirstJob, Some(Duration::from_secs(2))),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejected_authorize_closes_the_connection() {
+        let (mut client, updates) = test_client();
+        client.status = ClientStatus::Subscribed;
+        client
+            .sent_authorize_request
+            .push((2, String::from("observer")));
+        client
+            .sent_authorize_request
+            .push((3, String::from("observer")));
+
+        handle(&mut client, r#"{"id":2,"result":true,"error":null}"#);
+        assert!(client.is_authorized(&String::from("observer")));
+        assert!(!client.shutting_down);
+        handle(
+            &mut client,
+            r#"{"id":3,"result":null,"error":[24,"Unauthorized worker",null]}"#,
+        );
+        assert!(client.shutting_down);
+        let status = client.statuses.get("Test Pool").unwrap();
+        assert_eq!(status.state, ConnectionState::Rejected);
+        assert_eq!(
+            status.rejection_message.as_deref(),
+            Some(r#"[24,"Unauthorized worker",null]"#)
+        );
+        let events: Vec<_> = std::iter::from_fn(|| updates.try_recv().ok())
+            .map(|u| match u {
+                Update::Connection(event) => (event.kind, event.message),
+                u => panic!("expected a connection event, got {:?}", u),
+            })
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (ConnectionEventKind::Authorized, None),
+                (
+                    ConnectionEventKind::Rejected,
+                    Some(String::from(r#"[24,"Unauthorized worker",null]"#))
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn reconnect_follows_the_reconnect_policy() {
+        let (mut client, updates) = test_client();
+        client
+            .statuses
+            .set_state("Test Pool", ConnectionState::Live);
+        handle(
+            &mut client,
+            r#"{"id":null,"method":"client.reconnect","params":["evil.example.org",3333,0]}"#,
+        );
+        assert!(!client.shutting_down);
+        match updates.try_recv().unwrap() {
+            Update::Connection(event) => {
+                assert_eq!(event.kind, ConnectionEventKind::ReconnectRefused);
+                assert_eq!(
+                    event.message.as_deref(),
+                    Some("evil.example.org:3333 wait=0s")
+                );
+            }
+            u => panic!("expected a connection event, got {:?}", u),
+        }
+
+        handle(
+            &mut client,
+            r#"{"id":null,"method":"client.reconnect","params":["127.0.0.1","4444","3600"]}"#,
+        );
+        assert!(client.shutting_down);
+        assert_eq!(
+            client.statuses.get("Test Pool").unwrap().reconnect,
+            Some(ReconnectRequest {
+                endpoint: Some(String::from("127.0.0.1:4444")),
+                wait: Duration::from_secs(RECONNECT_MAX_WAIT_SECONDS),
+            })
+        );
+        match updates.try_recv().unwrap() {
+            Update::Connection(event) => assert_eq!(event.kind, ConnectionEventKind::Reconnect),
+            u => panic!("expected a connection event, got {:?}", u),
+        }
+
+        let (mut client, _) = test_client();
+        client.pool.reconnect_policy = ReconnectPolicy::Ignore;
+        handle(
+            &mut client,
+            r#"{"id":null,"method":"client.reconnect","params":[]}"#,
+        );
+        assert!(!client.shutting_down);
+    }
+
+    #[test]
+    fn show_message_is_recorded_as_event() {
+        let (mut client, updates) = test_client();
+        handle(
+            &mut client,
+            r#"{"id":null,"method":"client.show_message","params":["Maintenance at 12:00 UTC"]}"#,
+        );
+        match updates.try_recv().unwrap() {
+            Update::Connection(event) => {
+                assert_eq!(event.kind, ConnectionEventKind::PoolMessage);
+                assert_eq!(event.message.as_deref(), Some("Maintenance at 12:00 UTC"));
+            }
+            u => panic!("expected a connection event, got {:?}", u),
+        }
+    }
+
+    #[test]
+    fn set_extranonce_applies_to_later_jobs() {
+        let (mut client, updates) = test_client();
+        handle(&mut client, SUBSCRIBE_RESPONSE);
+        assert_eq!(client.extranonce1.as_ref(), &[0x08, 0x00, 0x00, 0x02]);
+        assert_eq!(client.extranonce2_size, 4);
+
+        handle(
+            &mut client,
+            r#"{"id":null,"method":"mining.set_extranonce","params":["aabbccdd11",8]}"#,
+        );
+        match try_recv_update(&updates) {
+            Update::Extranonce(e) => {
+                assert_eq!(e.previous_extranonce1, vec![0x08, 0x00, 0x00, 0x02]);
+                assert_eq!(e.previous_extranonce2_size, 4);
+                assert_eq!(e.extranonce1, vec![0xaa, 0xbb, 0xcc, 0xdd, 0x11]);
+                assert_eq!(e.extranonce2_size, 8);
+            }
+            u => panic!("expected an extranonce update, got {:?}", u),
+        }
+
+        handle(&mut client, NOTIFY);
+        match try_recv_update(&updates) {
+            Update::Job(job) => {
+                assert_eq!(job.extranonce1.as_ref(), &[0xaa, 0xbb, 0xcc, 0xdd, 0x11]);
+                assert_eq!(job.extranonce2_size, 8);
+            }
+            u => panic!("expected a job update, got {:?}", u),
+        }
+    }
+
+    #[test]
+    fn set_difficulty_is_tracked_and_attached_to_jobs() {
+        let (mut client, updates) = test_client();
+        handle(&mut client, SUBSCRIBE_RESPONSE);
+        handle(
+            &mut client,
+            r#"{"id":null,"method":"mining.set_difficulty","params":[1024]}"#,
+        );
+        match try_recv_update(&updates) {
+            Update::Difficulty(d) => {
+                assert_eq!(d.previous_difficulty, None);
+                assert_eq!(d.difficulty, 1024.0);
+            }
+            u => panic!("expected a difficulty update, got {:?}", u),
+        }
+
+        // an unchanged difficulty isn't recorded again
+        handle(
+            &mut client,
+            r#"{"id":null,"method":"mining.set_difficulty","params":[1024]}"#,
+        );
+        handle(
+            &mut client,
+            r#"{"id":null,"method":"mining.set_difficulty","params":[2048.5]}"#,
+        );
+        match try_recv_update(&updates) {
+            Update::Difficulty(d) => {
+                assert_eq!(d.previous_difficulty, Some(1024.0));
+                assert_eq!(d.difficulty, 2048.5);
+            }
+            u => panic!("expected a difficulty update, got {:?}", u),
+        }
+
+        handle(&mut client, NOTIFY);
+        match try_recv_update(&updates) {
+            Update::Job(job) => assert_eq!(job.difficulty, Some(2048.5)),
+            u => panic!("expected a job update, got {:?}", u),
+        }
+    }
+
+    #[test]
+    fn version_rolling_mask_is_negotiated_and_attached_to_jobs() {
+        let (mut client, updates) = test_client();
+        client.configure_request = Some((0, Instant::now()));
+        handle(
+            &mut client,
+            r#"{"id":0,"result":{"version-rolling":true,"version-rolling.mask":"1fffe000"},"error":null}"#,
+        );
+        assert!(matches!(client.status, ClientStatus::Configured));
+        handle(&mut client, SUBSCRIBE_RESPONSE);
+        handle(&mut client, NOTIFY);
+        match try_recv_update(&updates) {
+            Update::Job(job) => assert_eq!(job.version_rolling_mask, Some(0x1fffe000)),
+            u => panic!("expected a job update, got {:?}", u),
+        }
+
+        handle(
+            &mut client,
+            r#"{"id":null,"method":"mining.set_version_mask","params":["00ffe000"]}"#,
+        );
+        handle(&mut client, NOTIFY);
+        match try_recv_update(&updates) {
+            Update::Job(job) => assert_eq!(job.version_rolling_mask, Some(0x00ffe000)),
+            u => panic!("expected a job update, got {:?}", u),
+        }
+    }
+
+    #[test]
+    fn rejected_configure_disables_version_rolling() {
+        let (mut client, updates) = test_client();
+        client.configure_request = Some((0, Instant::now()));
+        handle(
+            &mut client,
+            r#"{"id":0,"result":null,"error":[20,"Unknown method",null]}"#,
+        );
+        assert!(matches!(client.status, ClientStatus::Configured));
+        handle(&mut client, SUBSCRIBE_RESPONSE);
+        handle(&mut client, NOTIFY);
+        match try_recv_update(&updates) {
+            Update::Job(job) => assert_eq!(job.version_rolling_mask, None),
+            u => panic!("expected a job update, got {:?}", u),
+        }
+    }
+
+    #[test]
+    fn configure_timeout_only_applies_before_the_response() {
+        let (mut client, _) = test_client();
+        let timeout = Duration::from_secs(CONFIGURE_RESPONSE_TIMEOUT_SECONDS + 1);
+        client.configure_request = Some((0, Instant::now() - timeout));
+        client.check_configure_timeout();
+        assert!(matches!(client.status, ClientStatus::Configured));
+
+        // a client that is already subscribed stays subscribed
+        client.status = ClientStatus::Subscribed;
+        client.check_configure_timeout();
+        assert!(matches!(client.status, ClientStatus::Subscribed));
+    }
+
+    /// Runs the client against the pool in a task. Returns the task, the client's updates
+    /// and the pool statuses.
+    fn spawn_client(
+        endpoint: String,
+    ) -> (
+        task::JoinHandle<()>,
+        Receiver<Update<'static>>,
+        PoolStatuses,
+    ) {
+        let pool = Pool::test([endpoint.clone()]);
+        let (update_sender, updates) = unbounded();
+        let statuses = PoolStatuses::default();
+        let client_statuses = statuses.clone();
+        let client = task::spawn(async move {
+            Client::run(
+                &pool,
+                &endpoint,
+                update_sender,
+                client_statuses,
+                None,
+                Rotation::default(),
+                ShutdownSignal::default(),
+            )
+            .await
+        });
+        (client, updates, statuses)
+    }
+
+    /// Waits for the next update `f` returns Some for, skipping the others.
+    async fn next_update<T>(
+        updates: &Receiver<Update<'static>>,
+        f: impl Fn(Update<'static>) -> Option<T>,
+    ) -> T {
+        loop {
+            let update = future::timeout(Duration::from_secs(5), updates.recv())
+                .await
+                .expect("should receive the update in time")
+                .expect("update channel should be open");
+            if let Some(t) = f(update) {
+                return t;
+            }
+        }
+    }
+
+    async fn next_job(updates: &Receiver<Update<'static>>) -> JobUpdate<'static> {
+        next_update(updates, |u| match u {
+            Update::Job(job) => Some(job),
+            _ => None,
+        })
+        .await
+    }
+
+    async fn next_event(
+        updates: &Receiver<Update<'static>>,
+        kind: ConnectionEventKind,
+    ) -> ConnectionEvent {
+        next_update(updates, |u| match u {
+            Update::Connection(event) if event.kind == kind => Some(event),
+            _ => None,
+        })
+        .await
+    }
+
+    async fn expect_client_stopped(client: task::JoinHandle<()>) {
+        future::timeout(Duration::from_secs(5), client)
+            .await
+            .expect("the client should stop in time");
+    }
+
+    #[async_std::test]
+    async fn receive_jobs_from_mock_pool() {
+        let mock = MockPool::bind().await;
+        let (client, updates, statuses) = spawn_client(mock.endpoint());
+        let mut connection = mock.accept().await;
+        connection.handshake().await;
+        next_event(&updates, ConnectionEventKind::Authorized).await;
+
+        connection
+            .notification("mining.set_difficulty", json!([1024]))
+            .await;
+        connection.notify("1", true).await;
+        let job = next_job(&updates).await;
+        assert_eq!(job.endpoint, mock.endpoint());
+        assert_eq!(job.difficulty, Some(1024.0));
+        assert_eq!(utils::encode_hex(job.extranonce1.as_ref()), EXTRANONCE1);
+        assert_eq!(job.extranonce2_size, EXTRANONCE2_SIZE);
+        assert_eq!(job.version_rolling_mask, Some(0x1fffe000));
+        assert_eq!(job.coinbase_info.height, 866888);
+        assert_eq!(job.coinbase_info.value_sum, 312_500_000);
+        let outputs = job.coinbase_info.outputs;
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].output_type, OutputType::P2pkh);
+        assert_eq!(outputs[0].value, 312_500_000);
+        assert_eq!(
+            statuses.get("Test Pool").unwrap().state,
+            ConnectionState::Live
+        );
+        let (_, notify) = statuses.get("Test Pool").unwrap().last_notify.unwrap();
+        assert!(notify.contains(r#""method":"mining.notify""#));
+
+        connection
+            .notification("mining.set_extranonce", json!(["deadbeef", 8]))
+            .await;
+        let extranonce = next_update(&updates, |u| match u {
+            Update::Extranonce(e) => Some(e),
+            _ => None,
+        })
+        .await;
+        assert_eq!(extranonce.extranonce1, vec![0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(extranonce.previous_extranonce2_size, EXTRANONCE2_SIZE);
+        connection.notify("2", false).await;
+        let job = next_job(&updates).await;
+        assert_eq!(job.extranonce1.as_ref(), &[0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(job.extranonce2_size, 8);
+
+        connection.disconnect();
+        next_event(&updates, ConnectionEventKind::RemoteClose).await;
+        expect_client_stopped(client).await;
+    }
+
+    #[async_std::test]
+    async fn follow_reconnect_from_mock_pool() {
+        let mock = MockPool::bind().await;
+        let (client, updates, statuses) = spawn_client(mock.endpoint());
+        let mut connection = mock.accept().await;
+        connection.handshake().await;
+        connection.notify("1", true).await;
+        next_job(&updates).await;
+
+        let port = mock.endpoint().rsplit_once(':').unwrap().1.to_string();
+        connection
+            .notification("client.reconnect", json!(["127.0.0.1", port, 0]))
+            .await;
+        next_event(&updates, ConnectionEventKind::Reconnect).await;
+        connection.expect_closed().await;
+        expect_client_stopped(client).await;
+        assert_eq!(
+            statuses.get("Test Pool").unwrap().reconnect,
+            Some(ReconnectRequest {
+                endpoint: None,
+                wait: Duration::ZERO,
+            })
+        );
+    }
+
+    #[async_std::test]
+    async fn replace_connection_before_closing_it_at_max_lifetime() {
+        let mock = MockPool::bind().await;
+        let pool = Pool {
+            max_lifetime: Some(1),
+            ..Pool::test([mock.endpoint()])
+        };
+        let (update_sender, updates) = unbounded();
+        let mut rotator = Rotator::default();
+        let spawn = |rotation: Rotation| {
+            let (pool, update_sender) = (pool.clone(), update_sender.clone());
+            task::spawn(async move {
+                let endpoint = pool.endpoints[0].clone();
+                Client::run(
+                    &pool,
+                    &endpoint,
+                    update_sender,
+                    PoolStatuses::default(),
+                    None,
+                    rotation,
+                    ShutdownSignal::default(),
+                )
+                .await
+            })
+        };
+
+        let old_client = spawn(rotator.next_connection());
+        let mut old = mock.accept().await;
+        old.handshake().await;
+        old.notify("1", true).await;
+        assert_eq!(next_job(&updates).await.job.job_id, "1");
+        future::timeout(Duration::from_secs(5), rotator.expired())
+            .await
+            .expect("the connection should expire after its max_lifetime");
+        rotator.rotate();
+
+        let new_client = spawn(rotator.next_connection());
+        let mut new = mock.accept().await;
+        new.handshake().await;
+        // the expired connection is still open and publishes jobs
+        old.notify("2", false).await;
+        assert_eq!(next_job(&updates).await.job.job_id, "2");
+        // the same template on the new connection is a duplicate and closes the old one
+        new.notify("a", false).await;
+        let event = next_event(&updates, ConnectionEventKind::MaxLifetime).await;
+        assert_eq!(event.message.as_deref(), Some("replaced"));
+        old.expect_closed().await;
+        expect_client_stopped(old_client).await;
+
+        // after the overlap, all jobs are published
+        new.notify("b", false).await;
+        assert_eq!(next_job(&updates).await.job.job_id, "b");
+        new.disconnect();
+        expect_client_stopped(new_client).await;
+    }
+
+    #[async_std::test]
+    async fn keep_expired_connection_open_until_it_times_out_when_not_replaced() {
+        let mock = MockPool::bind().await;
+        let pool = Pool {
+            max_lifetime: Some(1),
+            job_timeout: Some(2),
+            ..Pool::test([mock.endpoint()])
+        };
+        let (update_sender, updates) = unbounded();
+        let statuses = PoolStatuses::default();
+        let mut rotator = Rotator::default();
+        let spawn = |rotation: Rotation| {
+            let (pool, update_sender, statuses) =
+                (pool.clone(), update_sender.clone(), statuses.clone());
+            task::spawn(async move {
+                let endpoint = pool.endpoints[0].clone();
+                Client::run(
+                    &pool,
+                    &endpoint,
+                    update_sender,
+                    statuses,
+                    None,
+                    rotation,
+                    ShutdownSignal::default(),
+                )
+                .await
+            })
+        };
+
+        let old_client = spawn(rotator.next_connection());
+        let mut old = mock.accept().await;
+        old.handshake().await;
+        old.notify("1", true).await;
+        assert_eq!(next_job(&updates).await.job.job_id, "1");
+        future::timeout(Duration::from_secs(5), rotator.expired())
+            .await
+            .expect("the connection should expire after its max_lifetime");
+        rotator.rotate();
+
+        // the replacement closes before receiving a job
+        let replacement_client = spawn(rotator.next_connection());
+        mock.accept().await.disconnect();
+        expect_client_stopped(replacement_client).await;
+        rotator.closed();
+
+        // the expired connection is the only open connection and reports the pool's state
+        statuses.set_state(&pool.name, ConnectionState::Connecting);
+        old.notify("2", false).await;
+        assert_eq!(next_job(&updates).await.job.job_id, "2");
+        assert_eq!(
+            statuses.get(&pool.name).map(|s| s.state),
+            Some(ConnectionState::Live)
+        );
+
+        // the next connection doesn't replace it: its first job isn't a duplicate
+        let new_client = spawn(rotator.next_connection());
+        let mut new = mock.accept().await;
+        new.handshake().await;
+        new.notify("a", false).await;
+        assert_eq!(next_job(&updates).await.job.job_id, "a");
+
+        // the expired connection is closed after another job_timeout
+        let event = next_event(&updates, ConnectionEventKind::MaxLifetime).await;
+        assert_eq!(event.message.as_deref(), Some("not replaced"));
+        old.expect_closed().await;
+        expect_client_stopped(old_client).await;
+        new.disconnect();
+        expect_client_stopped(new_client).await;
+    }
+
+    #[async_std::test]
+    async fn close_connection_on_shutdown() {
+        let mock = MockPool::bind().await;
+        let pool = Pool::test([mock.endpoint()]);
+        let (update_sender, updates) = unbounded();
+        let shutdown = ShutdownSignal::default();
+        let client_shutdown = shutdown.clone();
+        let client = task::spawn(async move {
+            let endpoint = pool.endpoints[0].clone();
+            Client::run(
+                &pool,
+                &endpoint,
+                update_sender,
+                PoolStatuses::default(),
+                None,
+                Rotation::default(),
+                client_shutdown,
+            )
+            .await
+        });
+        let mut connection = mock.accept().await;
+        connection.handshake().await;
+        connection.notify("1", true).await;
+        next_job(&updates).await;
+
+        shutdown.trigger();
+        next_event(&updates, ConnectionEventKind::Shutdown).await;
+        connection.expect_closed().await;
+        expect_client_stopped(client).await;
+    }
+
+    #[async_std::test]
+    async fn stop_connecting_on_shutdown() {
+        // a TLS endpoint that accepts the connection, but never answers the handshake
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool = Pool::test([format!("stratum+ssl://{}", listener.local_addr().unwrap())]);
+        let (update_sender, updates) = unbounded();
+        let shutdown = ShutdownSignal::default();
+        let client_shutdown = shutdown.clone();
+        let client = task::spawn(async move {
+            let endpoint = pool.endpoints[0].clone();
+            Client::run(
+                &pool,
+                &endpoint,
+                update_sender,
+                PoolStatuses::default(),
+                None,
+                Rotation::default(),
+                client_shutdown,
+            )
+            .await
+        });
+        let (_stream, _) = listener.accept().await.unwrap();
+
+        shutdown.trigger();
+        next_event(&updates, ConnectionEventKind::Shutdown).await;
+        expect_client_stopped(client).await;
+    }
+
+    #[async_std::test]
+    async fn ignore_malformed_lines_from_mock_pool() {
+        let mock = MockPool::bind().await;
+        let (client, updates, _) = spawn_client(mock.endpoint());
+        let mut connection = mock.accept().await;
+        connection.handshake().await;
+
+        connection.send_line("this is not JSON").await;
+        connection
+            .send_line(r#"{"id":null,"method":"mining.notify","params":["truncated"]}"#)
+            .await;
+        connection
+            .send_line(r#"{"id":99,"result":true,"error":null}"#)
+            .await;
+        connection.notify("1", true).await;
+        assert_eq!(next_job(&updates).await.job.job_id, "1");
+
+        connection.disconnect();
+        expect_client_stopped(client).await;
+    }
+
+    #[async_std::test]
+    async fn close_connection_when_mock_pool_rejects_authorize() {
+        let mock = MockPool::bind().await;
+        let (client, updates, statuses) = spawn_client(mock.endpoint());
+        let mut connection = mock.accept().await;
+        let configure = connection.expect_request("mining.configure").await;
+        connection
+            .respond(&configure, json!({"version-rolling": false}))
+            .await;
+        let subscribe = connection.expect_request("mining.subscribe").await;
+        connection
+            .respond(&subscribe, json!([[], EXTRANONCE1, EXTRANONCE2_SIZE]))
+            .await;
+        connection
+            .expect_request("mining.extranonce.subscribe")
+            .await;
+        let authorize = connection.expect_request("mining.authorize").await;
+        assert_eq!(authorize["params"][0], "observer");
+        connection
+            .respond_error(&authorize, 24, "Unauthorized worker")
+            .await;
+
+        let event = next_event(&updates, ConnectionEventKind::Rejected).await;
+        assert!(event.message.unwrap().contains("Unauthorized worker"));
+        connection.expect_closed().await;
+        expect_client_stopped(client).await;
+        assert_eq!(
+            statuses.get("Test Pool").unwrap().state,
+            ConnectionState::Rejected
+        );
+    }
+}
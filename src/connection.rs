@@ -0,0 +1,653 @@
+use crate::transport::Endpoint;
+use crate::types::Pool;
+use async_channel::{bounded, Receiver, Sender};
+use async_std::future;
+use chrono::prelude::*;
+use chrono::TimeDelta;
+use log::{debug, info};
+use rand::Rng;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+/// Number of failed connection attempts in a row to an endpoint before failing over to the
+/// pool's next endpoint.
+const ENDPOINT_FAILOVER_ATTEMPTS: u32 = 3;
+
+/// The state of the connection to a pool.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionState {
+    /// Opening the TCP connection to the pool.
+    Connecting,
+    /// Connected. Setting up the stratum session (configure, subscribe and authorize or the
+    /// Stratum v2 Noise handshake and channel setup), but no job received yet.
+    Handshaking,
+    /// Receiving jobs from the pool.
+    Live,
+    /// The pool rejected our credentials.
+    Rejected,
+    /// Waiting until the given time before reconnecting.
+    BackingOff { until: DateTime<Utc> },
+    /// Disabled with the admin API: not connected until enabled again.
+    Disabled,
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectionState::Connecting => write!(f, "connecting"),
+            ConnectionState::Handshaking => write!(f, "handshaking"),
+            ConnectionState::Live => write!(f, "live"),
+            ConnectionState::Rejected => write!(f, "rejected"),
+            ConnectionState::BackingOff { until } => write!(f, "backing-off until {}", until),
+            ConnectionState::Disabled => write!(f, "disabled"),
+        }
+    }
+}
+
+/// Reconnect backoff configuration. After the n-th connection attempt in a row that didn't go
+/// live, we wait `initial_delay * multiplier^(n-1)` seconds, capped at `max_delay` and randomly
+/// shifted by up to +-`jitter` (a fraction of the delay), before reconnecting.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct Backoff {
+    /// Delay in seconds after the first failed connection attempt.
+    pub initial_delay: f64,
+    /// Maximum delay in seconds.
+    pub max_delay: f64,
+    pub multiplier: f64,
+    /// Random jitter as fraction of the delay, between 0 and 1.
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial_delay: 2.0,
+            max_delay: 600.0,
+            multiplier: 2.0,
+            jitter: 0.2,
+        }
+    }
+}
+
+impl Backoff {
+    /// The default backoff after a pool rejected our credentials. Reconnecting right away
+    /// won't help until the account is fixed, so we wait much longer.
+    pub fn rejected_default() -> Backoff {
+        Backoff {
+            initial_delay: 300.0,
+            max_delay: 21600.0,
+            multiplier: 2.0,
+            jitter: 0.2,
+        }
+    }
+
+    /// Checks that the delays are finite and non-negative, the multiplier is at least 1
+    /// and the jitter is between 0 and 1.
+    pub fn is_valid(&self) -> bool {
+        self.initial_delay.is_finite()
+            && self.initial_delay >= 0.0
+            && self.max_delay.is_finite()
+            && self.max_delay >= 0.0
+            && self.multiplier.is_finite()
+            && self.multiplier >= 1.0
+            && (0.0..=1.0).contains(&self.jitter)
+    }
+
+    /// The delay before reconnecting after `failures` failed connection attempts in a row.
+    pub fn delay(&self, failures: u32) -> Duration {
+        if failures == 0 {
+            return Duration::ZERO;
+        }
+        let exponent = i32::try_from(failures - 1).unwrap_or(i32::MAX);
+        let delay = (self.initial_delay * self.multiplier.powi(exponent)).min(self.max_delay);
+        let jitter = if self.jitter > 0.0 {
+            rand::thread_rng().gen_range(-self.jitter..=self.jitter)
+        } else {
+            0.0
+        };
+        Duration::from_secs_f64((delay * (1.0 + jitter)).max(0.0))
+    }
+}
+
+/// Which client.reconnect requests of a pool to follow. Following a request to reconnect
+/// to another host lets the pool redirect us anywhere, so this is restricted by default.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReconnectPolicy {
+    /// Ignore client.reconnect requests and keep the connection open.
+    Ignore,
+    /// Only follow requests to reconnect to the same host, possibly on another port.
+    #[default]
+    SameHost,
+    /// Follow requests to reconnect to any host.
+    Any,
+}
+
+impl ReconnectPolicy {
+    /// Checks if a reconnect from the current endpoint to the target endpoint is allowed.
+    pub fn allows(&self, current: &Endpoint, target: &Endpoint) -> bool {
+        match self {
+            ReconnectPolicy::Ignore => false,
+            ReconnectPolicy::SameHost => current.host.eq_ignore_ascii_case(&target.host),
+            ReconnectPolicy::Any => true,
+        }
+    }
+}
+
+/// A client.reconnect request of a pool we follow.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectRequest {
+    /// The endpoint to use for the next connection. None to reconnect to the current endpoint.
+    pub endpoint: Option<String>,
+    /// How long the pool asked us to wait before reconnecting.
+    pub wait: Duration,
+}
+
+/// A backoff configuration with optional values, to be able to use other defaults than
+/// [Backoff::default()] for unset values.
+#[derive(Debug, Deserialize, Clone, PartialEq, Default)]
+pub struct PartialBackoff {
+    pub initial_delay: Option<f64>,
+    pub max_delay: Option<f64>,
+    pub multiplier: Option<f64>,
+    pub jitter: Option<f64>,
+}
+
+impl PartialBackoff {
+    /// The backoff with unset values taken from `defaults`.
+    pub fn or(self, defaults: Backoff) -> Backoff {
+        Backoff {
+            initial_delay: self.initial_delay.unwrap_or(defaults.initial_delay),
+            max_delay: self.max_delay.unwrap_or(defaults.max_delay),
+            multiplier: self.multiplier.unwrap_or(defaults.multiplier),
+            jitter: self.jitter.unwrap_or(defaults.jitter),
+        }
+    }
+}
+
+/// Deserializes a backoff, using [Backoff::rejected_default()] for unset values.
+pub fn deserialize_rejected_backoff<'de, D>(deserializer: D) -> Result<Backoff, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(PartialBackoff::deserialize(deserializer)?.or(Backoff::rejected_default()))
+}
+
+/// A connection's side of make-before-break reconnects. When a connection reaches its
+/// max_lifetime, the pool task opens a replacement connection, and the old connection is only
+/// closed once the replacement received its first job, or after another job_timeout if it
+/// doesn't. Jobs that duplicate the last job of the other connection during this overlap are
+/// suppressed.
+#[derive(Debug, Clone, Default)]
+pub struct Rotation {
+    /// Identifies the connection among the pool's connections.
+    connection: u64,
+    /// Tells the pool task that the connection reached its max_lifetime.
+    expired: Option<Sender<()>>,
+    /// Signalled once the connection was replaced.
+    replaced: Option<Receiver<()>>,
+    /// Tells the connection this connection replaces that we received our first job.
+    predecessor: Option<Sender<()>>,
+    /// The connection and fingerprint of the last job published for the pool.
+    last_job: Arc<Mutex<Option<(u64, u64)>>>,
+    /// The pool's newest connection while it's open, or 0.
+    current: Arc<AtomicU64>,
+}
+
+impl Rotation {
+    /// Tells the pool task to open a replacement for the expired connection. Returns false if
+    /// the connection isn't rotated and should be closed right away.
+    pub fn expire(&self) -> bool {
+        self.expired
+            .as_ref()
+            .is_some_and(|expired| expired.try_send(()).is_ok())
+    }
+
+    /// Waits until the replacement of the expired connection received its first job. Never
+    /// returns if the replacement closes before.
+    pub async fn replaced(&self) {
+        if let Some(replaced) = &self.replaced {
+            if replaced.recv().await.is_ok() {
+                return;
+            }
+        }
+        future::pending().await
+    }
+
+    /// Whether no newer connection of the pool is open, so an expired connection reports
+    /// the pool's state.
+    pub fn is_current(&self) -> bool {
+        let current = self.current.load(Ordering::Relaxed);
+        current == 0 || current == self.connection
+    }
+
+    /// Records a job of the connection. Returns false if the job should be suppressed as it
+    /// duplicates the last job of the other connection during the overlap of an expired
+    /// connection and its replacement. The first job of a replacement closes the connection
+    /// it replaces.
+    pub fn publish(&self, fingerprint: u64, first_job: bool, expired: bool) -> bool {
+        let overlapping = expired || (first_job && self.predecessor.is_some());
+        let mut last_job = self
+            .last_job
+            .lock()
+            .expect("last job lock should not be poisoned");
+        let duplicate = overlapping
+            && last_job.is_some_and(|(connection, last)| {
+                connection != self.connection && last == fingerprint
+            });
+        *last_job = Some((self.connection, fingerprint));
+        if first_job {
+            if let Some(predecessor) = &self.predecessor {
+                let _ = predecessor.try_send(());
+            }
+        }
+        !duplicate
+    }
+}
+
+/// The pool task's side of make-before-break reconnects (see [Rotation]).
+#[derive(Debug, Default)]
+pub struct Rotator {
+    /// Closes the expired connection once its replacement received its first job.
+    predecessor: Option<Sender<()>>,
+    last_job: Arc<Mutex<Option<(u64, u64)>>>,
+    connections: u64,
+    /// Closes the current connection once it expired and was replaced.
+    replace: Option<Sender<()>>,
+    /// Signalled when the current connection expired.
+    expired: Option<Receiver<()>>,
+    current: Arc<AtomicU64>,
+}
+
+impl Rotator {
+    /// The rotation for the pool's next connection.
+    pub fn next_connection(&mut self) -> Rotation {
+        let (expired_sender, expired_receiver) = bounded(1);
+        let (replace_sender, replace_receiver) = bounded(1);
+        self.expired = Some(expired_receiver);
+        self.replace = Some(replace_sender);
+        self.connections += 1;
+        self.current.store(self.connections, Ordering::Relaxed);
+        // Only the first connection after a rotation replaces the expired connection. If it
+        // closes before receiving a job, the expired connection stays open until it times out.
+        Rotation {
+            connection: self.connections,
+            expired: Some(expired_sender),
+            replaced: Some(replace_receiver),
+            predecessor: self.predecessor.take(),
+            last_job: self.last_job.clone(),
+            current: self.current.clone(),
+        }
+    }
+
+    /// The current connection closed.
+    pub fn closed(&self) {
+        self.current.store(0, Ordering::Relaxed);
+    }
+
+    /// Waits until the current connection reached its max_lifetime. Never returns if the
+    /// connection closes before.
+    pub async fn expired(&self) {
+        if let Some(expired) = &self.expired {
+            if expired.recv().await.is_ok() {
+                return;
+            }
+        }
+        future::pending().await
+    }
+
+    /// The current connection expired: the next connection replaces it.
+    pub fn rotate(&mut self) {
+        self.predecessor = self.replace.take();
+    }
+}
+
+/// The connection status of a pool.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolStatus {
+    pub state: ConnectionState,
+    /// Time of the last state change.
+    pub since: DateTime<Utc>,
+    /// Number of connection attempts in a row that didn't go live.
+    pub failures: u32,
+    /// Index of the pool endpoint we currently connect to.
+    pub endpoint: usize,
+    /// Number of connection attempts in a row to the current endpoint that didn't go live.
+    pub endpoint_failures: u32,
+    /// Number of connections in a row on which the pool rejected our credentials.
+    pub rejections: u32,
+    /// The error message of the pool's last rejection of our credentials.
+    pub rejection_message: Option<String>,
+    /// A client.reconnect request received on the current connection.
+    pub reconnect: Option<ReconnectRequest>,
+    /// The endpoint a pool redirected us to with client.reconnect. Only used for the next
+    /// connection.
+    pub redirect: Option<String>,
+    /// The time and raw line of the last mining.notify received from the pool.
+    pub last_notify: Option<(DateTime<Utc>, String)>,
+}
+
+impl PoolStatus {
+    fn new(state: ConnectionState) -> PoolStatus {
+        PoolStatus {
+            state,
+            since: Utc::now(),
+            failures: 0,
+            endpoint: 0,
+            endpoint_failures: 0,
+            rejections: 0,
+            rejection_message: None,
+            reconnect: None,
+            redirect: None,
+            last_notify: None,
+        }
+    }
+}
+
+/// The connection status of all pools, shared between the pool tasks and the rest of the
+/// program.
+#[derive(Debug, Clone, Default)]
+pub struct PoolStatuses(Arc<RwLock<BTreeMap<String, PoolStatus>>>);
+
+impl PoolStatuses {
+    pub fn get(&self, pool: &str) -> Option<PoolStatus> {
+        self.0
+            .read()
+            .expect("pool status lock should not be poisoned")
+            .get(pool)
+            .cloned()
+    }
+
+    pub fn set_state(&self, pool: &str, state: ConnectionState) {
+        let mut statuses = self
+            .0
+            .write()
+            .expect("pool status lock should not be poisoned");
+        let status = statuses
+            .entry(pool.to_string())
+            .or_insert_with(|| PoolStatus::new(state));
+        if status.state != state {
+            debug!(
+                "Pool '{}' connection state changed from {} to {}",
+                pool, status.state, state
+            );
+            status.state = state;
+            status.since = Utc::now();
+        }
+    }
+
+    /// Keeps the raw mining.notify line last received from the pool.
+    pub fn notified(&self, pool: &str, line: &str, received: DateTime<Utc>) {
+        let mut statuses = self
+            .0
+            .write()
+            .expect("pool status lock should not be poisoned");
+        if let Some(status) = statuses.get_mut(pool) {
+            status.last_notify = Some((received, line.to_string()));
+        }
+    }
+
+    /// Forgets the status of a pool that was removed or will be restarted.
+    pub fn remove(&self, pool: &str) {
+        self.0
+            .write()
+            .expect("pool status lock should not be poisoned")
+            .remove(pool);
+    }
+
+    /// Marks the connection as rejected by the pool, keeping the pool's error message.
+    pub fn rejected(&self, pool: &str, message: String) {
+        self.set_state(pool, ConnectionState::Rejected);
+        let mut statuses = self
+            .0
+            .write()
+            .expect("pool status lock should not be poisoned");
+        if let Some(status) = statuses.get_mut(pool) {
+            status.rejection_message = Some(message);
+        }
+    }
+
+    /// Records a client.reconnect request the pool's client follows once the connection
+    /// is closed.
+    pub fn reconnect_requested(&self, pool: &str, request: ReconnectRequest) {
+        let mut statuses = self
+            .0
+            .write()
+            .expect("pool status lock should not be poisoned");
+        if let Some(status) = statuses.get_mut(pool) {
+            status.reconnect = Some(request);
+        }
+    }
+
+    /// The pool endpoint to connect to. This is the endpoint the pool redirected us to, if
+    /// any, or the configured endpoint we currently use.
+    pub fn endpoint(&self, pool: &Pool) -> String {
+        let status = self.get(&pool.name);
+        if let Some(redirect) = status.as_ref().and_then(|s| s.redirect.clone()) {
+            return redirect;
+        }
+        let index = status.map(|s| s.endpoint).unwrap_or_default();
+        pool.endpoints
+            .get(index)
+            .or(pool.endpoints.first())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Transitions the pool into the backing-off state after a connection ended and returns
+    /// how long to wait before reconnecting. A connection that went live resets the backoff,
+    /// so we reconnect right away, for example, after the max_lifetime was reached. After
+    /// repeated failures, we fail over to the pool's next endpoint. Rejected credentials
+    /// don't count as failures but use the separate `rejected_backoff`, and don't cause a
+    /// fail-over as all endpoints of a pool share the accounts. If the pool asked us to
+    /// reconnect, we wait at least as long as requested and use the endpoint it redirected
+    /// us to for the next connection.
+    pub fn disconnected(
+        &self,
+        pool: &Pool,
+        backoff: &Backoff,
+        rejected_backoff: &Backoff,
+    ) -> Duration {
+        let mut statuses = self
+            .0
+            .write()
+            .expect("pool status lock should not be poisoned");
+        let status = statuses
+            .entry(pool.name.clone())
+            .or_insert_with(|| PoolStatus::new(ConnectionState::Connecting));
+        let reconnect = status.reconnect.take();
+        status.redirect = reconnect.as_ref().and_then(|r| r.endpoint.clone());
+        let delay = if status.state == ConnectionState::Live {
+            status.failures = 0;
+            status.endpoint_failures = 0;
+            status.rejections = 0;
+            Duration::ZERO
+        } else if status.state == ConnectionState::Rejected {
+            status.rejections = status.rejections.saturating_add(1);
+            rejected_backoff.delay(status.rejections)
+        } else {
+            status.failures = status.failures.saturating_add(1);
+            status.endpoint_failures += 1;
+            if status.endpoint_failures >= ENDPOINT_FAILOVER_ATTEMPTS && pool.endpoints.len() > 1 {
+                status.endpoint = (status.endpoint + 1) % pool.endpoints.len();
+                status.endpoint_failures = 0;
+                info!(
+                    "Pool '{}' failed {} times in a row: failing over to endpoint {}",
+                    pool.name, ENDPOINT_FAILOVER_ATTEMPTS, pool.endpoints[status.endpoint]
+                );
+            }
+            backoff.delay(status.failures)
+        };
+        let delay = delay.max(reconnect.map(|r| r.wait).unwrap_or_default());
+        status.state = ConnectionState::BackingOff {
+            until: Utc::now() + TimeDelta::from_std(delay).unwrap_or_default(),
+        };
+        status.since = Utc::now();
+        delay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_delay_grows_exponentially_and_is_capped() {
+        let backoff = Backoff {
+            initial_delay: 1.0,
+            max_delay: 10.0,
+            multiplier: 2.0,
+            jitter: 0.0,
+        };
+        assert_eq!(backoff.delay(0), Duration::ZERO);
+        assert_eq!(backoff.delay(1), Duration::from_secs(1));
+        assert_eq!(backoff.delay(2), Duration::from_secs(2));
+        assert_eq!(backoff.delay(4), Duration::from_secs(8));
+        assert_eq!(backoff.delay(5), Duration::from_secs(10));
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(10));
+
+        let backoff = Backoff {
+            jitter: 0.5,
+            ..backoff
+        };
+        for _ in 0..100 {
+            let delay = backoff.delay(3);
+            assert!(delay >= Duration::from_secs(2) && delay <= Duration::from_secs(6));
+        }
+    }
+
+    #[test]
+    fn live_connection_resets_backoff() {
+        let backoff = Backoff {
+            jitter: 0.0,
+            ..Backoff::default()
+        };
+        let pool = Pool::test(["stratum.example.com:3333"]);
+        let statuses = PoolStatuses::default();
+        statuses.set_state("Test Pool", ConnectionState::Connecting);
+        assert_eq!(
+            statuses.disconnected(&pool, &backoff, &Backoff::rejected_default()),
+            Duration::from_secs(2)
+        );
+        statuses.set_state("Test Pool", ConnectionState::Handshaking);
+        assert_eq!(
+            statuses.disconnected(&pool, &backoff, &Backoff::rejected_default()),
+            Duration::from_secs(4)
+        );
+        assert_eq!(statuses.get("Test Pool").unwrap().failures, 2);
+        assert!(matches!(
+            statuses.get("Test Pool").unwrap().state,
+            ConnectionState::BackingOff { .. }
+        ));
+
+        statuses.set_state("Test Pool", ConnectionState::Live);
+        assert_eq!(
+            statuses.disconnected(&pool, &backoff, &Backoff::rejected_default()),
+            Duration::ZERO
+        );
+        assert_eq!(statuses.get("Test Pool").unwrap().failures, 0);
+    }
+
+    #[test]
+    fn rejected_credentials_use_their_own_backoff() {
+        let backoff = Backoff {
+            jitter: 0.0,
+            ..Backoff::default()
+        };
+        let rejected_backoff = Backoff {
+            jitter: 0.0,
+            ..Backoff::rejected_default()
+        };
+        let pool = Pool::test(["primary.example.com:3333", "backup.example.com:3333"]);
+        let statuses = PoolStatuses::default();
+        for rejections in 1..=ENDPOINT_FAILOVER_ATTEMPTS {
+            statuses.set_state("Test Pool", ConnectionState::Handshaking);
+            statuses.rejected("Test Pool", String::from("Unauthorized worker"));
+            assert_eq!(
+                statuses.disconnected(&pool, &backoff, &rejected_backoff),
+                rejected_backoff.delay(rejections)
+            );
+        }
+        let status = statuses.get("Test Pool").unwrap();
+        assert_eq!(status.rejections, ENDPOINT_FAILOVER_ATTEMPTS);
+        assert_eq!(status.failures, 0);
+        assert_eq!(status.endpoint, 0);
+        assert_eq!(
+            status.rejection_message.as_deref(),
+            Some("Unauthorized worker")
+        );
+
+        statuses.set_state("Test Pool", ConnectionState::Live);
+        statuses.disconnected(&pool, &backoff, &rejected_backoff);
+        assert_eq!(statuses.get("Test Pool").unwrap().rejections, 0);
+    }
+
+    #[test]
+    fn fail_over_to_next_endpoint_after_repeated_failures() {
+        let backoff = Backoff::default();
+        let pool = Pool::test(["primary.example.com:3333", "backup.example.com:3333"]);
+        let statuses = PoolStatuses::default();
+        assert_eq!(statuses.endpoint(&pool), "primary.example.com:3333");
+        for _ in 0..ENDPOINT_FAILOVER_ATTEMPTS {
+            assert_eq!(statuses.endpoint(&pool), "primary.example.com:3333");
+            statuses.set_state("Test Pool", ConnectionState::Connecting);
+            statuses.disconnected(&pool, &backoff, &Backoff::rejected_default());
+        }
+        assert_eq!(statuses.endpoint(&pool), "backup.example.com:3333");
+
+        // stay on the endpoint that works
+        statuses.set_state("Test Pool", ConnectionState::Live);
+        statuses.disconnected(&pool, &backoff, &Backoff::rejected_default());
+        assert_eq!(statuses.endpoint(&pool), "backup.example.com:3333");
+
+        for _ in 0..ENDPOINT_FAILOVER_ATTEMPTS {
+            statuses.set_state("Test Pool", ConnectionState::Connecting);
+            statuses.disconnected(&pool, &backoff, &Backoff::rejected_default());
+        }
+        assert_eq!(statuses.endpoint(&pool), "primary.example.com:3333");
+    }
+
+    #[test]
+    fn reconnect_requests_redirect_the_next_connection_only() {
+        let backoff = Backoff {
+            jitter: 0.0,
+            ..Backoff::default()
+        };
+        let pool = Pool::test(["primary.example.com:3333"]);
+        let statuses = PoolStatuses::default();
+        statuses.set_state("Test Pool", ConnectionState::Live);
+        statuses.reconnect_requested(
+            "Test Pool",
+            ReconnectRequest {
+                endpoint: Some(String::from("primary.example.com:4444")),
+                wait: Duration::from_secs(5),
+            },
+        );
+        assert_eq!(
+            statuses.disconnected(&pool, &backoff, &Backoff::rejected_default()),
+            Duration::from_secs(5)
+        );
+        assert_eq!(statuses.endpoint(&pool), "primary.example.com:4444");
+
+        // the redirected endpoint didn't work out: back to the configured endpoint
+        statuses.set_state("Test Pool", ConnectionState::Connecting);
+        statuses.disconnected(&pool, &backoff, &Backoff::rejected_default());
+        assert_eq!(statuses.endpoint(&pool), "primary.example.com:3333");
+    }
+
+    #[test]
+    fn reconnect_policy_restricts_redirects() {
+        let current = Endpoint::parse("stratum.example.com:3333").unwrap();
+        let other_port = Endpoint::parse("Stratum.Example.com:4444").unwrap();
+        let other_host = Endpoint::parse("evil.example.org:3333").unwrap();
+        assert!(!ReconnectPolicy::Ignore.allows(&current, &current));
+        assert!(ReconnectPolicy::SameHost.allows(&current, &other_port));
+        assert!(!ReconnectPolicy::SameHost.allows(&current, &other_host));
+        assert!(ReconnectPolicy::Any.allows(&current, &other_host));
+    }
+}
@@ -0,0 +1,165 @@
+//! Reads the request line and headers of the HTTP requests to the metrics and admin servers.
+
+use async_std::future;
+use async_std::io::{prelude::BufReadExt, BufReader, Read, ReadExt};
+use std::time::Duration;
+use std::{error, fmt, io};
+
+/// The time a client has to send the request line and headers.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// The maximum size of the request line and headers in bytes.
+pub const MAX_HEAD_BYTES: usize = 8192;
+/// The maximum number of headers.
+const MAX_HEADERS: usize = 64;
+
+/// The request line and headers of a HTTP request.
+#[derive(Debug, Default, PartialEq)]
+pub struct RequestHead {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    /// The value of the first header with the name, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    Timeout,
+    TooLarge,
+    Io(io::Error),
+}
+
+impl ReadError {
+    /// The status of the response to a request that could not be read.
+    pub fn status(&self) -> &'static str {
+        match self {
+            ReadError::Timeout => "408 Request Timeout",
+            ReadError::TooLarge => "431 Request Header Fields Too Large",
+            ReadError::Io(_) => "400 Bad Request",
+        }
+    }
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReadError::Timeout => write!(
+                f,
+                "the request headers were not received within {}s",
+                READ_TIMEOUT.as_secs()
+            ),
+            ReadError::TooLarge => write!(
+                f,
+                "the request headers are larger than {} bytes or more than {} lines",
+                MAX_HEAD_BYTES, MAX_HEADERS
+            ),
+            ReadError::Io(e) => write!(f, "the request could not be read: {}", e),
+        }
+    }
+}
+
+impl error::Error for ReadError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            ReadError::Timeout => None,
+            ReadError::TooLarge => None,
+            ReadError::Io(ref e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for ReadError {
+    fn from(err: io::Error) -> ReadError {
+        ReadError::Io(err)
+    }
+}
+
+/// Reads the request line and headers, for at most READ_TIMEOUT and MAX_HEAD_BYTES.
+pub async fn read_request_head<R: Read + Unpin>(reader: R) -> Result<RequestHead, ReadError> {
+    future::timeout(READ_TIMEOUT, read_head(reader))
+        .await
+        .unwrap_or(Err(ReadError::Timeout))
+}
+
+async fn read_head<R: Read + Unpin>(reader: R) -> Result<RequestHead, ReadError> {
+    // one byte more than allowed to tell a request that's too large from one that fits
+    let mut reader = BufReader::new(reader.take(MAX_HEAD_BYTES as u64 + 1));
+    let mut read = 0;
+    let mut head = RequestHead::default();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let n = reader.read_line(&mut line).await?;
+        read += n;
+        if read > MAX_HEAD_BYTES {
+            return Err(ReadError::TooLarge);
+        }
+        let trimmed = line.trim_end_matches(['\r', '\n']);
+        if n == 0 || (trimmed.is_empty() && !head.method.is_empty()) {
+            return Ok(head);
+        }
+        if head.method.is_empty() {
+            let mut parts = trimmed.split(' ');
+            head.method = parts.next().unwrap_or_default().to_string();
+            head.path = parts.next().unwrap_or_default().to_string();
+        } else if let Some((name, value)) = trimmed.split_once(':') {
+            if head.headers.len() == MAX_HEADERS {
+                return Err(ReadError::TooLarge);
+            }
+            head.headers
+                .push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn read_request_line_and_headers() {
+        let request =
+            "GET /pools HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer secret\r\n\r\nbody";
+        let head = read_request_head(request.as_bytes()).await.unwrap();
+        assert_eq!(head.method, "GET");
+        assert_eq!(head.path, "/pools");
+        assert_eq!(head.header("authorization"), Some("Bearer secret"));
+        assert_eq!(head.header("Content-Length"), None);
+
+        // a connection closed before the end of the headers
+        let head = read_request_head("GET /metrics HTTP/1.1\r\n".as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(head.path, "/metrics");
+    }
+
+    #[async_std::test]
+    async fn reject_too_large_requests() {
+        let request = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_HEAD_BYTES));
+        assert!(matches!(
+            read_request_head(request.as_bytes()).await,
+            Err(ReadError::TooLarge)
+        ));
+
+        let request = format!(
+            "GET / HTTP/1.1\r\n{}\r\n",
+            "X: y\r\n".repeat(MAX_HEADERS + 1)
+        );
+        assert!(matches!(
+            read_request_head(request.as_bytes()).await,
+            Err(ReadError::TooLarge)
+        ));
+
+        let request = format!("GET / HTTP/1.1\r\n{}\r\n", "X: y\r\n".repeat(MAX_HEADERS));
+        let head = read_request_head(request.as_bytes()).await.unwrap();
+        assert_eq!(head.headers.len(), MAX_HEADERS);
+    }
+}
@@ -0,0 +1,300 @@
+//! Identifies the pool a job is for by its coinbase tag and output addresses, using a
+//! mempool.space-style pools.json. Both the pools-v2.json list and the legacy pools.json
+//! object with coinbase_tags and payout_addresses are supported.
+
+use crate::types::JobUpdate;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::{error, fmt, fs, io};
+
+/// How a pool was identified.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IdentificationMethod {
+    /// A coinbase output pays to one of the pool's addresses.
+    Address,
+    /// The coinbase contains one of the pool's tags.
+    Tag,
+}
+
+impl fmt::Display for IdentificationMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IdentificationMethod::Address => write!(f, "address"),
+            IdentificationMethod::Tag => write!(f, "tag"),
+        }
+    }
+}
+
+/// The pool a job was identified as.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolIdentification {
+    /// The name of the pool in the pools.json.
+    pub name: String,
+    pub method: IdentificationMethod,
+    /// True if the identified pool doesn't match the configured pool name, e.g. for
+    /// white-label pools and proxies.
+    pub mismatch: bool,
+}
+
+#[derive(Deserialize)]
+struct PoolEntry {
+    name: String,
+    #[serde(default)]
+    addresses: Vec<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct LegacyPoolEntry {
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PoolsJson {
+    V2(Vec<PoolEntry>),
+    Legacy {
+        coinbase_tags: BTreeMap<String, LegacyPoolEntry>,
+        payout_addresses: BTreeMap<String, LegacyPoolEntry>,
+    },
+}
+
+#[derive(Debug)]
+pub enum IdentifyError {
+    ReadError(io::Error),
+    JsonError(serde_json::Error),
+}
+
+impl fmt::Display for IdentifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IdentifyError::ReadError(e) => write!(f, "the pools.json could not be read: {}", e),
+            IdentifyError::JsonError(e) => write!(f, "the pools.json could not be parsed: {}", e),
+        }
+    }
+}
+
+impl error::Error for IdentifyError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            IdentifyError::ReadError(ref e) => Some(e),
+            IdentifyError::JsonError(ref e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for IdentifyError {
+    fn from(err: io::Error) -> IdentifyError {
+        IdentifyError::ReadError(err)
+    }
+}
+
+impl From<serde_json::Error> for IdentifyError {
+    fn from(err: serde_json::Error) -> IdentifyError {
+        IdentifyError::JsonError(err)
+    }
+}
+
+/// The known pools' coinbase tags and payout addresses.
+#[derive(Debug, Default)]
+pub struct KnownPools {
+    /// (tag, pool name) in the order of the pools.json.
+    tags: Vec<(String, String)>,
+    /// Pool names by payout address.
+    addresses: BTreeMap<String, String>,
+}
+
+impl KnownPools {
+    pub fn load(path: &str) -> Result<KnownPools, IdentifyError> {
+        KnownPools::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(json: &str) -> Result<KnownPools, IdentifyError> {
+        let mut known = KnownPools::default();
+        match serde_json::from_str(json)? {
+            PoolsJson::V2(pools) => {
+                for pool in pools {
+                    for tag in pool.tags.into_iter().filter(|t| !t.is_empty()) {
+                        known.tags.push((tag, pool.name.clone()));
+                    }
+                    for address in pool.addresses {
+                        known.addresses.insert(address, pool.name.clone());
+                    }
+                }
+            }
+            PoolsJson::Legacy {
+                coinbase_tags,
+                payout_addresses,
+            } => {
+                known.tags = coinbase_tags
+                    .into_iter()
+                    .filter(|(tag, _)| !tag.is_empty())
+                    .map(|(tag, pool)| (tag, pool.name))
+                    .collect();
+                known.addresses = payout_addresses
+                    .into_iter()
+                    .map(|(address, pool)| (address, pool.name))
+                    .collect();
+            }
+        }
+        Ok(known)
+    }
+
+    /// Identifies the pool of the job. Output addresses take precedence over coinbase tags,
+    /// as tags are easier to copy.
+    pub fn identify(&self, job: &JobUpdate) -> Option<PoolIdentification> {
+        let coinbase_info = &job.coinbase_info;
+        let (name, method) = coinbase_info
+            .outputs
+            .iter()
+            .filter_map(|o| o.address.as_ref())
+            .find_map(|address| self.addresses.get(address))
+            .map(|name| (name, IdentificationMethod::Address))
+            .or_else(|| {
+                self.tags
+                    .iter()
+                    .find(|(tag, _)| coinbase_info.tag.contains(tag.as_str()))
+                    .map(|(_, name)| (name, IdentificationMethod::Tag))
+            })?;
+        Some(PoolIdentification {
+            name: name.clone(),
+            method,
+            mismatch: !names_match(name, &job.pool.name),
+        })
+    }
+}
+
+/// Compares pool names ignoring case, whitespace and punctuation. A name matches if it
+/// contains the other, e.g. "Foundry USA" and "Foundry".
+fn names_match(a: &str, b: &str) -> bool {
+    let normalize = |name: &str| -> String {
+        name.chars()
+            .filter(|c| c.is_alphanumeric())
+            .flat_map(char::to_lowercase)
+            .collect()
+    };
+    let (a, b) = (normalize(a), normalize(b));
+    !a.is_empty() && !b.is_empty() && (a.contains(&b) || b.contains(&a))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{CoinbaseInfo, Pool};
+    use crate::utils::decode_hex;
+    use binary_sv2::U256;
+    use bitcoin::Address;
+    use chrono::prelude::*;
+    use std::str::FromStr;
+    use sv1_api::server_to_client::Notify;
+    use sv1_api::utils::{Extranonce, HexU32Be, PrevHash};
+
+    const POOLS_V2: &str = r#"[
+        {"id": 1, "name": "Foundry USA", "addresses": ["12dRugNcdxK39288NjcDV4GX7rMsKCGn6B"], "tags": ["/2cDw/", "Foundry USA Pool"], "link": "https://foundrydigital.com"},
+        {"id": 2, "name": "Slush Pool", "addresses": [], "tags": ["/slush/"], "link": "https://slushpool.com"}
+    ]"#;
+
+    const POOLS_LEGACY: &str = r#"{
+        "coinbase_tags": {"/slush/": {"name": "Slush Pool", "link": "https://slushpool.com"}},
+        "payout_addresses": {"12dRugNcdxK39288NjcDV4GX7rMsKCGn6B": {"name": "Foundry USA", "link": ""}}
+    }"#;
+
+    /// A job whose coinbase contains the tag and pays to the address.
+    fn job(pool_name: &str, tag: &str, address: &str) -> JobUpdate<'static> {
+        let mut coinbase1 = decode_hex(
+            "01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff",
+        )
+        .unwrap();
+        let script_sig_len = 4 + tag.len() + 8;
+        coinbase1.push(script_sig_len as u8);
+        coinbase1.extend(decode_hex("0348390d").unwrap());
+        coinbase1.extend(tag.as_bytes());
+        let mut coinbase2 = decode_hex("ffffffff0100f2052a01000000").unwrap();
+        let script = Address::from_str(address)
+            .unwrap()
+            .assume_checked()
+            .script_pubkey()
+            .to_bytes();
+        coinbase2.push(script.len() as u8);
+        coinbase2.extend(script);
+        coinbase2.extend(decode_hex("00000000").unwrap());
+        let pool = Pool {
+            name: pool_name.to_string(),
+            ..Pool::default()
+        };
+        let job = Notify {
+            job_id: String::from("1"),
+            prev_hash: PrevHash(U256::from([0u8; 32])),
+            coin_base1: coinbase1.into(),
+            coin_base2: coinbase2.into(),
+            merkle_branch: vec![],
+            version: HexU32Be(0x20000000),
+            bits: HexU32Be(0x1703255b),
+            time: HexU32Be(0x66b3f3b0),
+            clean_jobs: true,
+        };
+        let extranonce1 = Extranonce::try_from(vec![0u8; 4]).unwrap();
+        let coinbase_info = Box::new(CoinbaseInfo::decode(&job, &extranonce1, 4, pool.network()));
+        JobUpdate {
+            timestamp: Utc::now(),
+            pool,
+            endpoint: String::from("127.0.0.1:3333"),
+            job,
+            extranonce1,
+            extranonce2_size: 4,
+            difficulty: None,
+            version_rolling_mask: None,
+            time_connected: Utc::now(),
+            coinbase_info,
+            identification: None,
+            witness_commitment_changed: false,
+        }
+    }
+
+    const FOUNDRY_ADDRESS: &str = "12dRugNcdxK39288NjcDV4GX7rMsKCGn6B";
+    const OTHER_ADDRESS: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+
+    #[test]
+    fn identify_pools_by_address_and_tag() {
+        for json in [POOLS_V2, POOLS_LEGACY] {
+            let known = KnownPools::parse(json).unwrap();
+
+            let identification = known
+                .identify(&job("Foundry", "/slush/", FOUNDRY_ADDRESS))
+                .unwrap();
+            assert_eq!(identification.name, "Foundry USA");
+            assert_eq!(identification.method, IdentificationMethod::Address);
+            assert!(!identification.mismatch);
+
+            let identification = known
+                .identify(&job("Foundry", "/slush/", OTHER_ADDRESS))
+                .unwrap();
+            assert_eq!(identification.name, "Slush Pool");
+            assert_eq!(identification.method, IdentificationMethod::Tag);
+            assert!(identification.mismatch);
+
+            assert_eq!(
+                known.identify(&job("Foundry", "/unknown/", OTHER_ADDRESS)),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn match_pool_names() {
+        assert!(names_match("Foundry USA", "foundry"));
+        assert!(names_match("F2Pool", "f2pool"));
+        assert!(names_match("SlushPool", "Slush Pool"));
+        assert!(!names_match("Foundry USA", "AntPool"));
+        assert!(!names_match("", "AntPool"));
+    }
+
+    #[test]
+    fn reject_invalid_pools_json() {
+        assert!(matches!(
+            KnownPools::parse(r#"{"pools": []}"#),
+            Err(IdentifyError::JsonError(_))
+        ));
+    }
+}
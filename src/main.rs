@@ -1,27 +1,63 @@
-use crate::schema::job_updates;
+use crate::admin::AdminCommand;
+use crate::schema::{
+    coinbase_outputs, connection_events, difficulty_updates, extranonce_updates, job_updates,
+    tls_updates,
+};
+use crate::types::ConnectionEventJson;
 use crate::types::JobUpdate;
 use crate::types::JobUpdateJson;
+use crate::types::NewCoinbaseOutput;
+use crate::types::NewConnectionEvent;
+use crate::types::NewDifficultyUpdate;
+use crate::types::NewExtranonceUpdate;
 use crate::types::NewJobUpdate;
+use crate::types::NewTlsUpdate;
+use crate::types::Update;
+use crate::types::WitnessCommitments;
 use async_broadcast::broadcast;
 use async_channel::{unbounded, Receiver};
 use async_std::sync::Arc;
 use async_std::sync::RwLock;
-use async_std::task;
-use client::Client;
+use async_std::{future, task};
+use config::Config;
+use connection::PoolStatuses;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 use env_logger::Env;
+use futures::future::{select, Either};
+use identify::KnownPools;
 use log::{debug, error, info, warn};
+use metrics::Metrics;
+use replay::ReplayOptions;
+use shutdown::ShutdownSignal;
 use std::collections::BTreeMap;
+use std::env;
+use std::io;
 use std::net::TcpListener;
+use std::pin::pin;
 use std::process::exit;
 use std::time::Duration;
+use supervisor::Supervisor;
 use tungstenite::accept;
 
+mod admin;
+mod capture;
 mod client;
 mod config;
+mod connection;
+mod http;
+mod identify;
+mod metrics;
+#[cfg(test)]
+mod mock_pool;
+mod reload;
+mod replay;
 mod schema;
+mod shutdown;
+mod supervisor;
+mod sv2_client;
+mod transport;
 mod types;
 mod utils;
 
@@ -30,97 +66,319 @@ pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("./migrations/");
 #[async_std::main]
 async fn main() {
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => observe().await,
+        Some("replay") => replay_captures(&args[1..]).await,
+        Some(_) => {
+            eprintln!("usage: stratum-observer [replay ...]");
+            eprintln!("{}", replay::USAGE);
+            exit(4)
+        }
+    }
+}
+
+/// Replays capture files through the Stratum v1 client and into the database and websocket,
+/// or prints the updates in a dry-run.
+async fn replay_captures(args: &[String]) {
+    let options = match ReplayOptions::parse(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(4)
+        }
+    };
+    // The config is only needed for the pool settings in a dry-run.
     let config = match config::load_config() {
+        Ok(c) => Some(c),
+        Err(e) if options.dry_run => {
+            info!("Replaying without configuration: {}", e);
+            None
+        }
+        Err(e) => panic!("could not load config: {}", e),
+    };
+    let pools = config.as_ref().map(|c| c.pools.clone()).unwrap_or_default();
+
+    let (update_sender, update_receiver) = unbounded();
+    let sinks = if options.dry_run {
+        task::spawn(print_updates(update_receiver))
+    } else {
+        let config = config.expect("config should be loaded when not in a dry-run");
+        task::spawn(async move { process_updates(&config, update_receiver).await })
+    };
+    if let Err(e) = replay::replay(&options, &pools, update_sender).await {
+        error!("Could not replay captures: {}", e);
+        exit(5)
+    }
+    sinks.await;
+}
+
+/// Prints the updates to stdout until the update channel is closed.
+async fn print_updates(receiver: Receiver<Update<'static>>) {
+    while let Ok(update) = receiver.recv().await {
+        match &update {
+            Update::Extranonce(e) => println!(
+                "extranonce {} {} {} -> {} {}",
+                e.pool.name,
+                utils::encode_hex(&e.previous_extranonce1),
+                e.previous_extranonce2_size,
+                utils::encode_hex(&e.extranonce1),
+                e.extranonce2_size
+            ),
+            Update::Difficulty(d) => println!(
+                "difficulty {} {:?} -> {}",
+                d.pool.name, d.previous_difficulty, d.difficulty
+            ),
+            Update::Tls(t) => println!(
+                "tls {} {} {} {}",
+                t.pool.name,
+                t.tls.protocol_version,
+                t.tls.cipher_suite,
+                t.tls.certificate_fingerprint
+            ),
+            Update::Job(_) | Update::Connection(_) => match websocket_message(&update) {
+                Some(Ok(msg)) => println!("{}", msg),
+                Some(Err(e)) => warn!("Could not serialize update to JSON: {}", e),
+                None => (),
+            },
+        }
+    }
+}
+
+/// Connects to the configured pools and processes their updates until the observer is
+/// shut down. The pools are reconfigured when the configuration is reloaded and controlled
+/// with the admin API.
+async fn observe() {
+    let mut config = match config::load_config() {
         Ok(c) => c,
         Err(e) => panic!("could not load config: {}", e),
     };
 
-    let (job_sender, job_receiver) = unbounded();
+    let shutdown = ShutdownSignal::default();
+    shutdown::handle_signals(shutdown.clone(), config.shutdown_timeout());
+    let reload_requests = reload::reload_requests(config::config_file_path());
+
+    let (update_sender, update_receiver) = unbounded();
+    let updates = task::spawn({
+        let config = config.clone();
+        async move { process_updates(&config, update_receiver).await }
+    });
 
-    for pool in config.pools.clone() {
-        let js = job_sender.clone();
+    let pool_statuses = PoolStatuses::default();
+    let (admin_sender, admin_commands) = unbounded();
+    if let (Some(address), Some(token)) = (config.admin_address.clone(), config.admin_token.clone())
+    {
+        let statuses = pool_statuses.clone();
         task::spawn(async move {
-            debug!("Spawned task for pool: '{}'", pool.name);
-            // reopen the connection when a client or we close the connection
-            loop {
-                Client::run(&pool, js.clone()).await;
+            admin::serve(&address, token, statuses, admin_sender).await;
+            exit(1)
+        });
+    }
+
+    let mut supervisor = Supervisor::new(update_sender, pool_statuses);
+    supervisor.apply(&config).await;
+    loop {
+        let reload = pin!(reload_requests.recv());
+        // the admin channel is closed if the admin API isn't enabled
+        let command = pin!(async {
+            match admin_commands.recv().await {
+                Ok(command) => command,
+                Err(_) => future::pending().await,
             }
         });
+        let command = match select(select(reload, command), pin!(shutdown.wait())).await {
+            Either::Left((Either::Left(_), _)) => None,
+            Either::Left((Either::Right((command, _)), _)) => Some(command),
+            Either::Right(_) => break,
+        };
+        match command {
+            Some(AdminCommand::List(respond)) => {
+                let _ = respond.send(supervisor.pools()).await;
+            }
+            Some(AdminCommand::Control(name, action, respond)) => {
+                let found = supervisor.control(&name, action).await;
+                let _ = respond.send(found).await;
+            }
+            None => config = reload_config(&mut supervisor, config).await,
+        }
+    }
+    // the update channel closes once all pool tasks and clients stopped
+    supervisor.stop().await;
+
+    updates.await;
+    info!("Shut down");
+}
+
+/// Reloads the configuration and applies it to the pool tasks. Returns the applied
+/// configuration, which is the current one if the new one is invalid.
+async fn reload_config(supervisor: &mut Supervisor, config: Config) -> Config {
+    let new_config = match config::load_config() {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Could not reload config, keeping the current one: {}", e);
+            return config;
+        }
+    };
+    let restart_required = config.restart_required(&new_config);
+    if !restart_required.is_empty() {
+        warn!(
+            "Changes to {} are only applied on a restart",
+            restart_required.join(", ")
+        );
     }
+    supervisor.apply(&new_config).await;
+    new_config
+}
 
+/// Starts the database writer and websocket tasks and forwards the updates to them until
+/// the update channel is closed. Then waits until the database writer and websocket tasks
+/// are done.
+async fn process_updates(config: &Config, update_receiver: Receiver<Update<'static>>) {
     let enable_database = config.postgresql_url.is_some();
     let enable_websocket = config.websocket_address.is_some();
-    if !enable_database && !enable_websocket {
-        warn!("Neither database_path nor websocket_address are set: nothing to do");
+    if !enable_database && !enable_websocket && config.metrics_address.is_none() {
+        warn!(
+            "Neither database_path, websocket_address nor metrics_address are set: nothing to do"
+        );
         exit(3)
     }
 
-    let (db_sender, db_receiver) = unbounded();
-    let (websocket_sender, websocket_receiver) = unbounded();
+    let known_pools = match &config.pools_json {
+        Some(path) => match KnownPools::load(path) {
+            Ok(known_pools) => {
+                info!("Identifying pools with {}", path);
+                Some(known_pools)
+            }
+            Err(e) => {
+                error!("Could not load {}: {}", path, e);
+                exit(3)
+            }
+        },
+        None => None,
+    };
 
-    // websocket sender task
-    if let Some(ws_addr) = config.websocket_address.clone() {
+    let mut witness_commitments = WitnessCommitments::default();
+    let metrics = Metrics::default();
+    if let Some(metrics_addr) = config.metrics_address.clone() {
+        let metrics = metrics.clone();
         task::spawn(async move {
-            websocket_sender_task(websocket_receiver, &ws_addr).await;
+            metrics::serve(metrics, &metrics_addr).await;
             exit(1)
         });
+    }
+
+    let (db_sender, db_receiver) = unbounded();
+    let (websocket_sender, websocket_receiver) = unbounded();
+
+    // websocket sender task
+    let websocket = if let Some(ws_addr) = config.websocket_address.clone() {
+        Some(task::spawn(async move {
+            if let Err(e) = websocket_sender_task(websocket_receiver, &ws_addr).await {
+                error!("Could not start websocket server on {}: {}", ws_addr, e);
+                exit(1)
+            }
+        }))
     } else {
         websocket_sender.close();
         websocket_receiver.close();
-    }
+        None
+    };
 
     // database writer task
-    if let Some(db_url) = config.postgresql_url.clone() {
-        task::spawn(async move {
+    let db_writer = if let Some(db_url) = config.postgresql_url.clone() {
+        Some(task::spawn(async move {
             db_writer_task(db_receiver, &db_url).await;
-            exit(2)
-        });
+        }))
     } else {
         db_sender.close();
         db_receiver.close();
-    }
+        None
+    };
 
     // main task
-    // handles new jobs
+    // handles new jobs and other updates
     task::spawn(async move {
         loop {
-            match job_receiver.recv().await {
-                Ok(job) => {
+            match update_receiver.recv().await {
+                Ok(mut update) => {
+                    if let Update::Job(job) = &mut update {
+                        job.witness_commitment_changed = witness_commitments.changed(job);
+                        if job.witness_commitment_changed {
+                            warn!(
+                                "Witness commitment of job {} from '{}' changed, but its merkle branches didn't",
+                                job.job.job_id, job.pool.name
+                            );
+                        }
+                    }
+                    if let (Update::Job(job), Some(known_pools)) = (&mut update, &known_pools) {
+                        job.identification = known_pools.identify(job);
+                        if let Some(identification) = &job.identification {
+                            if identification.mismatch {
+                                debug!(
+                                    "Job from '{}' identified as '{}' by its coinbase {}",
+                                    job.pool.name, identification.name, identification.method
+                                );
+                            }
+                        }
+                    }
+                    metrics.record(&update);
                     if enable_database {
-                        if let Err(e) = db_sender.send(job.clone()).await {
-                            error!("could not send a job to the database task: {}", e);
+                        if let Err(e) = db_sender.send(update.clone()).await {
+                            error!("could not send an update to the database task: {}", e);
                             break;
                         }
                     }
                     if enable_websocket {
-                        if let Err(e) = websocket_sender.send(job).await {
-                            error!("could not send a job to the websocket task: {}", e);
-                            break;
+                        if let Update::Job(_) | Update::Connection(_) = update {
+                            if let Err(e) = websocket_sender.send(update).await {
+                                error!("could not send an update to the websocket task: {}", e);
+                                break;
+                            }
                         }
                     }
                 }
-                Err(e) => {
-                    error!("could not receive a new job in main thread: {}", e);
+                Err(_) => {
+                    debug!("update channel closed");
                     break;
                 }
             }
         }
-        info!("main loop exited..")
+        info!("main loop exited..");
+        db_sender.close();
+        websocket_sender.close();
     })
     .await;
+
+    if let Some(db_writer) = db_writer {
+        db_writer.await;
+    }
+    if let Some(websocket) = websocket {
+        websocket.await;
+    }
 }
 
-async fn websocket_sender_task(receiver: Receiver<JobUpdate<'static>>, ws_addr: &str) {
+/// Serializes job updates and connection events for the websocket. Other updates aren't
+/// sent to websocket clients.
+fn websocket_message(update: &Update<'static>) -> Option<serde_json::Result<String>> {
+    match update {
+        Update::Job(job) => Some(serde_json::to_string::<JobUpdateJson>(&job.clone().into())),
+        Update::Connection(event) => Some(serde_json::to_string::<ConnectionEventJson>(
+            &event.clone().into(),
+        )),
+        _ => None,
+    }
+}
+
+/// Serves the updates to websocket clients until the update channel is closed. Then closes
+/// the websockets.
+async fn websocket_sender_task(
+    receiver: Receiver<Update<'static>>,
+    ws_addr: &str,
+) -> io::Result<()> {
     info!("Starting websocket server on {}", ws_addr);
-    let server = match TcpListener::bind(ws_addr) {
-        Ok(s) => s,
-        Err(e) => {
-            error!("Could not start websocket server on {}: {}", ws_addr, e);
-            return;
-        }
-    };
+    let server = TcpListener::bind(ws_addr)?;
 
-    // a broadcast channel is used to fan-out the jobs to all websocket
+    // a broadcast channel is used to fan-out the updates to all websocket
     // subscribers. One 'inactive' receiver is just used for cloning new
     // broadcast receivers. The sender overflows to make sure we allways
     // empty the websocket_receiver channel, even when no active websocket
@@ -131,27 +389,44 @@ async fn websocket_sender_task(receiver: Receiver<JobUpdate<'static>>, ws_addr:
     let recent_jobs: BTreeMap<String, JobUpdate> = BTreeMap::new();
     let recent_jobs_rwl_arc = Arc::new(RwLock::new(recent_jobs));
 
-    let recent_jobs_write = recent_jobs_rwl_arc.clone();
-    task::spawn(async move {
-        loop {
-            let job = receiver.recv().await.unwrap();
-            {
-                // store the most recent job in a map to be able to send
-                // it to newly connecting clients
-                let mut recent_jobs_ = recent_jobs_write.write().await;
-                recent_jobs_.insert(job.pool.name.clone(), job.clone());
-            }
-            if sender.receiver_count() > 0 {
-                sender.broadcast(job.clone()).await.unwrap();
-                debug!(
-                    "broadcast new job by '{}' to {} websocket thread(s)",
-                    job.pool.name,
-                    sender.receiver_count()
-                );
-            }
-        }
+    // accepting connections blocks, so it's done on a blocking thread
+    let recent_jobs_read = recent_jobs_rwl_arc.clone();
+    task::spawn_blocking(move || {
+        accept_websockets(server, inactive_broadcast_receiver, recent_jobs_read)
     });
 
+    while let Ok(update) = receiver.recv().await {
+        if let Update::Job(job) = &update {
+            // store the most recent job in a map to be able to send
+            // it to newly connecting clients
+            let mut recent_jobs_ = recent_jobs_rwl_arc.write().await;
+            recent_jobs_.insert(job.pool.name.clone(), job.clone());
+        }
+        if sender.receiver_count() > 0 {
+            sender.broadcast(update).await.unwrap();
+            debug!(
+                "broadcast update to {} websocket thread(s)",
+                sender.receiver_count()
+            );
+        }
+    }
+
+    // closing the broadcast channel makes the websocket tasks send their remaining updates
+    // and close the websockets
+    sender.close();
+    while sender.receiver_count() > 0 {
+        task::sleep(Duration::from_millis(10)).await;
+    }
+    info!("Websocket task stopped: no more updates");
+    Ok(())
+}
+
+/// Accepts websocket connections and sends them the recent jobs and the broadcast updates.
+fn accept_websockets(
+    server: TcpListener,
+    inactive_broadcast_receiver: async_broadcast::InactiveReceiver<Update<'static>>,
+    recent_jobs_rwl_arc: Arc<RwLock<BTreeMap<String, JobUpdate<'static>>>>,
+) {
     for stream in server.incoming() {
         match stream {
             Ok(stream) => {
@@ -166,64 +441,37 @@ async fn websocket_sender_task(receiver: Receiver<JobUpdate<'static>>, ws_addr:
                             );
 
                             // send recent jobs when websocket is opened
-                            {
-                                let recent_jobs_ = recent_jobs_read.read().await;
-                                for job in recent_jobs_.values() {
-                                    // TODO: deduplicate sending jobs into websocket code
-                                    match serde_json::to_string::<JobUpdateJson>(
-                                        &job.clone().into(),
-                                    ) {
-                                        Ok(msg) => {
-                                            if let Err(e) =
-                                                websocket.send(tungstenite::Message::Text(msg))
-                                            {
-                                                debug!("Could not send '{}' job update to websocket: {}. Connection probably closed.", job.pool.name, e);
-                                                // Try our best to close and flush the websocket. If we can't,
-                                                // we can't..
-                                                let _ = websocket.close(None);
-                                                let _ = websocket.flush();
-                                                break;
-                                            }
-                                        }
-                                        Err(e) => {
-                                            warn!(
-                                                "Could not serialize JobUpdateJson to JSON: {}",
-                                                e
-                                            )
-                                        }
-                                    }
+                            let recent_updates: Vec<Update<'static>> = recent_jobs_read
+                                .read()
+                                .await
+                                .values()
+                                .map(|job| Update::Job(job.clone()))
+                                .collect();
+                            let mut open = true;
+                            for update in recent_updates {
+                                if !send_websocket_update(&mut websocket, &update) {
+                                    open = false;
+                                    break;
                                 }
                             }
 
-                            // continuesly send new jobs
-                            loop {
-                                let job = match r.recv().await {
-                                    Ok(j) => j,
+                            // continuesly send new updates
+                            while open {
+                                let update = match r.recv().await {
+                                    Ok(u) => u,
+                                    Err(async_broadcast::RecvError::Closed) => break,
                                     Err(e) => {
                                         warn!(
-                                            "Could not receive broadcast-job for websocket: {}",
+                                            "Could not receive broadcast-update for websocket: {}",
                                             e
                                         );
                                         break;
                                     }
                                 };
-                                // TODO: deduplicate sending jobs into websocket code
-                                match serde_json::to_string::<JobUpdateJson>(&job.clone().into()) {
-                                    Ok(msg) => {
-                                        if let Err(e) =
-                                            websocket.send(tungstenite::Message::Text(msg))
-                                        {
-                                            debug!("Could not send '{}' job update to websocket: {}. Connection probably closed.", job.pool.name, e);
-                                            // Try our best to close and flush the websocket. If we can't,
-                                            // we can't..
-                                            break;
-                                        }
-                                    }
-                                    Err(e) => {
-                                        warn!("Could not serialize JobUpdateJson to JSON: {}", e)
-                                    }
-                                }
+                                open = send_websocket_update(&mut websocket, &update);
                             }
+                            // Try our best to close and flush the websocket. If we can't,
+                            // we can't..
                             let _ = websocket.close(None);
                             let _ = websocket.flush();
                         }
@@ -240,7 +488,28 @@ async fn websocket_sender_task(receiver: Receiver<JobUpdate<'static>>, ws_addr:
     }
 }
 
-async fn db_writer_task(receiver: Receiver<JobUpdate<'_>>, db_url: &str) {
+/// Sends the update to the websocket. Returns false if the connection is closed.
+fn send_websocket_update(
+    websocket: &mut tungstenite::WebSocket<std::net::TcpStream>,
+    update: &Update<'static>,
+) -> bool {
+    match websocket_message(update) {
+        Some(Ok(msg)) => {
+            if let Err(e) = websocket.send(tungstenite::Message::Text(msg)) {
+                debug!(
+                    "Could not send update to websocket: {}. Connection probably closed.",
+                    e
+                );
+                return false;
+            }
+        }
+        Some(Err(e)) => warn!("Could not serialize update to JSON: {}", e),
+        None => (),
+    }
+    true
+}
+
+async fn db_writer_task(receiver: Receiver<Update<'_>>, db_url: &str) {
     info!("Started database writer task with database at {}", db_url);
     let mut first = true;
     loop {
@@ -250,12 +519,16 @@ async fn db_writer_task(receiver: Receiver<JobUpdate<'_>>, db_url: &str) {
                 discarded_jobs += 1;
                 let _ = receiver.recv().await;
             }
-            debug!("discarded {} jobs from the database writer channel to avoid it becoming too full..", discarded_jobs);
+            debug!("discarded {} updates from the database writer channel to avoid it becoming too full..", discarded_jobs);
+            if receiver.is_closed() {
+                info!("Database writer task stopped: no more updates");
+                return;
+            }
             task::sleep(Duration::from_secs(3)).await;
         }
         first = false;
 
-        let mut conn = match PgConnection::establish(&db_url) {
+        let mut conn = match PgConnection::establish(db_url) {
             Ok(conn) => conn,
             Err(e) => {
                 error!("Could not connect to database: {}", e);
@@ -274,17 +547,58 @@ async fn db_writer_task(receiver: Receiver<JobUpdate<'_>>, db_url: &str) {
         loop {
             let update = match receiver.recv().await {
                 Ok(update) => update,
-                Err(e) => {
-                    panic!("Could not receive job from database receiver: {}", e);
+                Err(_) => {
+                    info!("Database writer task stopped: no more updates");
+                    return;
                 }
             };
 
-            let v: NewJobUpdate = update.into();
-            if let Err(e) = diesel::insert_into(job_updates::table)
-                .values(&vec![v])
-                .execute(&mut conn)
-            {
-                error!("Could not insert job into database: {}", e);
+            let result = match update {
+                Update::Job(job) => {
+                    let outputs = job.coinbase_info.outputs.clone();
+                    let v: NewJobUpdate = job.into();
+                    conn.transaction(|conn| {
+                        let job_update_id: i32 = diesel::insert_into(job_updates::table)
+                            .values(&v)
+                            .returning(job_updates::id)
+                            .get_result(conn)?;
+                        let outputs: Vec<NewCoinbaseOutput> = outputs
+                            .into_iter()
+                            .enumerate()
+                            .map(|(i, o)| NewCoinbaseOutput::new(job_update_id, i, o))
+                            .collect();
+                        diesel::insert_into(coinbase_outputs::table)
+                            .values(&outputs)
+                            .execute(conn)
+                    })
+                }
+                Update::Extranonce(extranonce) => {
+                    let v: NewExtranonceUpdate = extranonce.into();
+                    diesel::insert_into(extranonce_updates::table)
+                        .values(&vec![v])
+                        .execute(&mut conn)
+                }
+                Update::Difficulty(difficulty) => {
+                    let v: NewDifficultyUpdate = difficulty.into();
+                    diesel::insert_into(difficulty_updates::table)
+                        .values(&vec![v])
+                        .execute(&mut conn)
+                }
+                Update::Tls(tls) => {
+                    let v: NewTlsUpdate = tls.into();
+                    diesel::insert_into(tls_updates::table)
+                        .values(&vec![v])
+                        .execute(&mut conn)
+                }
+                Update::Connection(event) => {
+                    let v: NewConnectionEvent = event.into();
+                    diesel::insert_into(connection_events::table)
+                        .values(&vec![v])
+                        .execute(&mut conn)
+                }
+            };
+            if let Err(e) = result {
+                error!("Could not insert update into database: {}", e);
                 break;
             }
         }
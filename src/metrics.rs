@@ -0,0 +1,216 @@
+//! Prometheus metrics about the pool connections, served as text over HTTP.
+
+use crate::http::{self, ReadError};
+use crate::types::{ConnectionEvent, ConnectionEventKind, Update};
+use async_std::io::WriteExt;
+use async_std::net::{TcpListener, TcpStream};
+use async_std::{prelude::*, task};
+use log::{debug, error, info, warn};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, RwLock};
+
+/// The latencies of a handshake phase of a pool's connections.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Latency {
+    /// Latency of the most recent connection in seconds.
+    last: f64,
+    /// Sum of all latencies in seconds.
+    sum: f64,
+    count: u64,
+}
+
+/// A pool name, endpoint and handshake phase.
+type LatencyKey = (String, String, &'static str);
+
+/// Metrics collected from the updates, shared with the metrics server.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    /// Latencies by (pool, endpoint, phase).
+    latencies: Arc<RwLock<BTreeMap<LatencyKey, Latency>>>,
+}
+
+/// The handshake phase a connection event's latency measures.
+fn latency_phase(kind: ConnectionEventKind) -> Option<&'static str> {
+    match kind {
+        ConnectionEventKind::Connected => Some("connect"),
+        ConnectionEventKind::Subscribed => Some("subscribe"),
+        ConnectionEventKind::Authorized => Some("authorize"),
+        ConnectionEventKind::FirstJob => Some("first_job"),
+        _ => None,
+    }
+}
+
+impl Metrics {
+    pub fn record(&self, update: &Update<'static>) {
+        if let Update::Connection(event) = update {
+            self.record_latency(event);
+        }
+    }
+
+    fn record_latency(&self, event: &ConnectionEvent) {
+        let (Some(latency), Some(phase)) = (event.latency, latency_phase(event.kind)) else {
+            return;
+        };
+        let mut latencies = self
+            .latencies
+            .write()
+            .expect("metrics lock should not be poisoned");
+        let entry = latencies
+            .entry((event.pool.name.clone(), event.endpoint.clone(), phase))
+            .or_default();
+        entry.last = latency.as_secs_f64();
+        entry.sum += latency.as_secs_f64();
+        entry.count += 1;
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let latencies = self
+            .latencies
+            .read()
+            .expect("metrics lock should not be poisoned");
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP stratum_observer_last_latency_seconds Latency of a phase of the most recent connection to a pool."
+        );
+        let _ = writeln!(out, "# TYPE stratum_observer_last_latency_seconds gauge");
+        for ((pool, endpoint, phase), latency) in latencies.iter() {
+            let _ = writeln!(
+                out,
+                "stratum_observer_last_latency_seconds{} {}",
+                labels(pool, endpoint, phase),
+                latency.last
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP stratum_observer_latency_seconds Latency of a phase of the connections to a pool."
+        );
+        let _ = writeln!(out, "# TYPE stratum_observer_latency_seconds summary");
+        for ((pool, endpoint, phase), latency) in latencies.iter() {
+            let labels = labels(pool, endpoint, phase);
+            let _ = writeln!(
+                out,
+                "stratum_observer_latency_seconds_sum{} {}",
+                labels, latency.sum
+            );
+            let _ = writeln!(
+                out,
+                "stratum_observer_latency_seconds_count{} {}",
+                labels, latency.count
+            );
+        }
+        out
+    }
+}
+
+fn labels(pool: &str, endpoint: &str, phase: &str) -> String {
+    format!(
+        r#"{{pool="{}",endpoint="{}",phase="{}"}}"#,
+        escape_label_value(pool),
+        escape_label_value(endpoint),
+        phase
+    )
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+/// Serves the metrics on GET /metrics.
+pub async fn serve(metrics: Metrics, address: &str) {
+    info!("Starting metrics server on {}", address);
+    let listener = match TcpListener::bind(address).await {
+        Ok(l) => l,
+        Err(e) => {
+            error!("Could not start metrics server on {}: {}", address, e);
+            return;
+        }
+    };
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        match stream {
+            Ok(stream) => {
+                let metrics = metrics.clone();
+                task::spawn(async move {
+                    if let Err(e) = handle_request(stream, &metrics).await {
+                        debug!("Could not answer metrics request: {}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("Failed to accept incoming metrics connection: {}", e),
+        }
+    }
+}
+
+async fn handle_request(stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    let (status, body) = match http::read_request_head(&stream).await {
+        Ok(head) if head.method == "GET" && head.path == "/metrics" => ("200 OK", metrics.render()),
+        Ok(_) => ("404 Not Found", String::from("not found\n")),
+        Err(ReadError::Io(e)) => return Err(e),
+        Err(e) => (e.status(), format!("{}\n", e)),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    let mut stream = stream;
+    stream.write_all(response.as_bytes()).await?;
+    stream.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Pool;
+    use std::time::Duration;
+
+    #[test]
+    fn record_and_render_latencies() {
+        let metrics = Metrics::default();
+        let pool = Pool {
+            name: String::from(r#"Test "Pool""#),
+            ..Pool::default()
+        };
+        for millis in [100, 300] {
+            let event = ConnectionEvent {
+                latency: Some(Duration::from_millis(millis)),
+                ..ConnectionEvent::new(&pool, "127.0.0.1:3333", ConnectionEventKind::Authorized)
+            };
+            metrics.record(&Update::Connection(event));
+        }
+        // events without a latency or phase aren't recorded
+        metrics.record(&Update::Connection(ConnectionEvent::new(
+            &pool,
+            "127.0.0.1:3333",
+            ConnectionEventKind::Connected,
+        )));
+        metrics.record(&Update::Connection(ConnectionEvent {
+            latency: Some(Duration::from_secs(1)),
+            ..ConnectionEvent::new(&pool, "127.0.0.1:3333", ConnectionEventKind::RemoteClose)
+        }));
+
+        let rendered = metrics.render();
+        let labels = r#"{pool="Test \"Pool\"",endpoint="127.0.0.1:3333",phase="authorize"}"#;
+        assert!(rendered.contains(&format!(
+            "stratum_observer_last_latency_seconds{} 0.3\n",
+            labels
+        )));
+        assert!(rendered.contains(&format!(
+            "stratum_observer_latency_seconds_sum{} 0.4\n",
+            labels
+        )));
+        assert!(rendered.contains(&format!(
+            "stratum_observer_latency_seconds_count{} 2\n",
+            labels
+        )));
+        assert_eq!(rendered.matches("phase=").count(), 3);
+    }
+}
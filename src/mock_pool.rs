@@ -0,0 +1,152 @@
+//! A scriptable in-process Stratum v1 pool for tests. Tests accept the client's connection
+//! and then drive it step by step: wait for requests, answer them, send notifications or
+//! malformed lines, and disconnect when they want to.
+
+use async_std::io::{prelude::BufReadExt, BufReader, Lines, WriteExt};
+use async_std::net::{TcpListener, TcpStream};
+use async_std::{future, prelude::*};
+use serde_json::{json, Value};
+use std::net::Shutdown;
+use std::time::Duration;
+
+/// How long to wait for the client before failing the test.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub const EXTRANONCE1: &str = "08000002";
+pub const EXTRANONCE2_SIZE: usize = 4;
+pub const VERSION_ROLLING_MASK: &str = "1fffe000";
+
+/// The params of a mining.notify for a block at height 866888 paying 3.125 BTC to a
+/// single output.
+pub fn notify_params(job_id: &str, clean_jobs: bool) -> Value {
+    json!([
+        job_id,
+        "4d16b6f85af6e2198f44ae2a6de67f78487ae5611b77c6c0440b921e00000000",
+        "01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff1503483a0d08",
+        "072f736c7573682f0000000001205fa012000000001976a914d23fcdf86f7e756a64a7a9688ef9903327048ed988ac00000000",
+        [],
+        "20000000",
+        "1c2ac4af",
+        "504e86b9",
+        clean_jobs
+    ])
+}
+
+pub struct MockPool {
+    listener: TcpListener,
+}
+
+impl MockPool {
+    pub async fn bind() -> MockPool {
+        MockPool {
+            listener: TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        }
+    }
+
+    /// The host:port endpoint to configure for the client.
+    pub fn endpoint(&self) -> String {
+        self.listener.local_addr().unwrap().to_string()
+    }
+
+    /// Waits for the next client connection.
+    pub async fn accept(&self) -> MockConnection {
+        let (stream, _) = future::timeout(TIMEOUT, self.listener.accept())
+            .await
+            .expect("the client should connect in time")
+            .unwrap();
+        MockConnection {
+            lines: BufReader::new(stream.clone()).lines(),
+            stream,
+        }
+    }
+}
+
+pub struct MockConnection {
+    stream: TcpStream,
+    lines: Lines<BufReader<TcpStream>>,
+}
+
+impl MockConnection {
+    /// Waits for the next request and checks its method. Returns the request.
+    pub async fn expect_request(&mut self, method: &str) -> Value {
+        let line = future::timeout(TIMEOUT, self.lines.next())
+            .await
+            .unwrap_or_else(|_| panic!("the client should send {} in time", method))
+            .expect("the client should not close the connection")
+            .unwrap();
+        let request: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(request["method"], method, "unexpected request: {}", line);
+        request
+    }
+
+    /// Sends a raw line. A newline is appended.
+    pub async fn send_line(&mut self, line: &str) {
+        self.stream
+            .write_all(format!("{}\n", line).as_bytes())
+            .await
+            .unwrap();
+    }
+
+    pub async fn respond(&mut self, request: &Value, result: Value) {
+        let response = json!({"id": request["id"], "result": result, "error": null});
+        self.send_line(&response.to_string()).await;
+    }
+
+    pub async fn respond_error(&mut self, request: &Value, code: i64, message: &str) {
+        let response = json!({"id": request["id"], "result": null, "error": [code, message, null]});
+        self.send_line(&response.to_string()).await;
+    }
+
+    pub async fn notification(&mut self, method: &str, params: Value) {
+        let notification = json!({"id": null, "method": method, "params": params});
+        self.send_line(&notification.to_string()).await;
+    }
+
+    pub async fn notify(&mut self, job_id: &str, clean_jobs: bool) {
+        self.notification("mining.notify", notify_params(job_id, clean_jobs))
+            .await;
+    }
+
+    /// Answers mining.configure (allowing version rolling), mining.subscribe,
+    /// mining.extranonce.subscribe and mining.authorize.
+    pub async fn handshake(&mut self) {
+        let configure = self.expect_request("mining.configure").await;
+        self.respond(
+            &configure,
+            json!({"version-rolling": true, "version-rolling.mask": VERSION_ROLLING_MASK}),
+        )
+        .await;
+        let subscribe = self.expect_request("mining.subscribe").await;
+        self.respond(
+            &subscribe,
+            json!([
+                [["mining.notify", "ae6812eb4cd7735a302a8a9dd95cf71f"]],
+                EXTRANONCE1,
+                EXTRANONCE2_SIZE
+            ]),
+        )
+        .await;
+        let extranonce_subscribe = self.expect_request("mining.extranonce.subscribe").await;
+        self.respond(&extranonce_subscribe, json!(true)).await;
+        let authorize = self.expect_request("mining.authorize").await;
+        self.respond(&authorize, json!(true)).await;
+    }
+
+    /// Waits until the client closes the connection.
+    pub async fn expect_closed(&mut self) {
+        loop {
+            match future::timeout(TIMEOUT, self.lines.next())
+                .await
+                .expect("the client should close the connection in time")
+            {
+                None | Some(Err(_)) => return,
+                Some(Ok(_)) => continue,
+            }
+        }
+    }
+
+    /// Closes the connection like a pool going away.
+    pub fn disconnect(self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
@@ -0,0 +1,96 @@
+//! Requests a configuration reload on SIGHUP and when the configuration file changes.
+
+use async_channel::{unbounded, Receiver, Sender};
+use async_std::task;
+use futures::StreamExt;
+use log::{debug, error, info};
+use signal_hook::consts::SIGHUP;
+use signal_hook_async_std::Signals;
+use std::fs;
+use std::time::{Duration, SystemTime};
+
+/// How often the modification time of the configuration file is checked.
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Returns a channel that receives a message when the configuration should be reloaded.
+pub fn reload_requests(config_file_path: String) -> Receiver<()> {
+    let (sender, receiver) = unbounded();
+    handle_sighup(sender.clone());
+    task::spawn(watch_config_file(config_file_path, sender, WATCH_INTERVAL));
+    receiver
+}
+
+fn handle_sighup(sender: Sender<()>) {
+    let mut signals = match Signals::new([SIGHUP]) {
+        Ok(signals) => signals,
+        Err(e) => {
+            error!("Could not register SIGHUP handler: {}", e);
+            return;
+        }
+    };
+    task::spawn(async move {
+        while signals.next().await.is_some() {
+            info!("Received SIGHUP: reloading the configuration");
+            if sender.send(()).await.is_err() {
+                return;
+            }
+        }
+    });
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Requests a reload when the modification time of the file changes.
+async fn watch_config_file(path: String, sender: Sender<()>, interval: Duration) {
+    let mut last_modified = modified(&path);
+    loop {
+        task::sleep(interval).await;
+        let modified = modified(&path);
+        if modified != last_modified {
+            last_modified = modified;
+            if modified.is_none() {
+                debug!("Configuration file {} is gone", path);
+                continue;
+            }
+            info!("Configuration file {} changed: reloading", path);
+            if sender.send(()).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::future;
+    use std::fs::File;
+
+    #[async_std::test]
+    async fn request_reload_when_config_file_changes() {
+        let path = std::env::temp_dir().join(format!(
+            "stratum-observer-reload-test-{}.toml",
+            std::process::id()
+        ));
+        fs::write(&path, "pools = []").unwrap();
+        let (sender, receiver) = unbounded();
+        task::spawn(watch_config_file(
+            path.to_string_lossy().to_string(),
+            sender,
+            Duration::from_millis(10),
+        ));
+
+        task::sleep(Duration::from_millis(50)).await;
+        assert!(receiver.is_empty());
+        let file = File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(1))
+            .unwrap();
+        future::timeout(Duration::from_secs(1), receiver.recv())
+            .await
+            .expect("a reload should be requested")
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
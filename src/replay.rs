@@ -0,0 +1,291 @@
+use crate::capture::CaptureRecord;
+use crate::client::Client;
+use crate::types::{Pool, Update};
+use async_channel::Sender;
+use async_std::task;
+use chrono::prelude::*;
+use log::{debug, info, warn};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::{error, fmt};
+
+pub const USAGE: &str =
+    "usage: stratum-observer replay [--realtime] [--dry-run] <capture.jsonl>...";
+
+/// Options of the replay mode.
+#[derive(Debug, PartialEq)]
+pub struct ReplayOptions {
+    /// Wait between the lines as long as between the original lines instead of replaying
+    /// them as fast as possible.
+    pub realtime: bool,
+    /// Print the updates instead of writing them to the database and websocket.
+    pub dry_run: bool,
+    /// The capture files to replay.
+    pub files: Vec<String>,
+}
+
+impl ReplayOptions {
+    pub fn parse(args: &[String]) -> Result<ReplayOptions, ReplayError> {
+        let mut options = ReplayOptions {
+            realtime: false,
+            dry_run: false,
+            files: vec![],
+        };
+        for arg in args {
+            match arg.as_str() {
+                "--realtime" => options.realtime = true,
+                "--dry-run" => options.dry_run = true,
+                a if a.starts_with("--") => return Err(ReplayError::Usage),
+                file => options.files.push(file.to_string()),
+            }
+        }
+        if options.files.is_empty() {
+            return Err(ReplayError::Usage);
+        }
+        Ok(options)
+    }
+}
+
+/// A captured line with its parsed timestamp.
+struct ReplayRecord {
+    timestamp: DateTime<Utc>,
+    record: CaptureRecord,
+}
+
+/// Reads the capture files and orders the records of all files by time.
+fn read_records(files: &[String]) -> Result<Vec<ReplayRecord>, ReplayError> {
+    let mut records = vec![];
+    for file in files {
+        let reader = BufReader::new(File::open(file)?);
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: CaptureRecord = serde_json::from_str(&line).map_err(|e| {
+                ReplayError::InvalidRecord(format!("{}:{}: {}", file, number + 1, e))
+            })?;
+            let timestamp = DateTime::parse_from_rfc3339(&record.timestamp)
+                .map_err(|e| ReplayError::InvalidRecord(format!("{}:{}: {}", file, number + 1, e)))?
+                .with_timezone(&Utc);
+            records.push(ReplayRecord { timestamp, record });
+        }
+    }
+    // stable, so lines with the same timestamp keep their order
+    records.sort_by_key(|r| r.timestamp);
+    Ok(records)
+}
+
+/// Feeds the captured lines through the Stratum v1 [Client] message handling, which
+/// publishes the updates to `update_sender` as if they had just been received. Pools are
+/// looked up by name in `pools`. Pools that aren't configured anymore are replayed with
+/// the default settings.
+pub async fn replay(
+    options: &ReplayOptions,
+    pools: &[Pool],
+    update_sender: Sender<Update<'static>>,
+) -> Result<(), ReplayError> {
+    let records = read_records(&options.files)?;
+    info!(
+        "Replaying {} captured lines from {} file(s)",
+        records.len(),
+        options.files.len()
+    );
+    let Some(first) = records.first().map(|r| r.timestamp) else {
+        return Ok(());
+    };
+    let started = Utc::now();
+    let mut clients: BTreeMap<String, Client<'static>> = BTreeMap::new();
+    for ReplayRecord { timestamp, record } in records {
+        if options.realtime {
+            let due = started + (timestamp - first);
+            if let Ok(delay) = (due - Utc::now()).to_std() {
+                task::sleep(delay).await;
+            }
+        }
+        let client = clients
+            .entry(record.connection_id.clone())
+            .or_insert_with(|| {
+                debug!(
+                    "Replaying connection {} to '{}' at {}",
+                    record.connection_id, record.pool, record.endpoint
+                );
+                let pool = pools
+                    .iter()
+                    .find(|p| p.name == record.pool)
+                    .cloned()
+                    .unwrap_or_else(|| {
+                        warn!(
+                            "Pool '{}' isn't configured: replaying it with the default settings",
+                            record.pool
+                        );
+                        Pool {
+                            name: record.pool.clone(),
+                            endpoints: vec![record.endpoint.clone()],
+                            ..Default::default()
+                        }
+                    });
+                Client::for_replay(
+                    &pool,
+                    record.endpoint.clone(),
+                    update_sender.clone(),
+                    timestamp,
+                )
+            });
+        client.replay_line(record.direction, &record.line, timestamp);
+    }
+    info!("Replayed {} connection(s)", clients.len());
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Usage,
+    ReadError(io::Error),
+    InvalidRecord(String),
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplayError::Usage => write!(f, "{}", USAGE),
+            ReplayError::ReadError(e) => write!(f, "the capture file could not be read: {}", e),
+            ReplayError::InvalidRecord(e) => write!(f, "invalid capture record: {}", e),
+        }
+    }
+}
+
+impl error::Error for ReplayError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            ReplayError::Usage => None,
+            ReplayError::ReadError(ref e) => Some(e),
+            ReplayError::InvalidRecord(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for ReplayError {
+    fn from(err: io::Error) -> ReplayError {
+        ReplayError::ReadError(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::CaptureConfig;
+    use crate::capture::{CaptureWriter, Direction};
+    use async_channel::unbounded;
+
+    #[test]
+    fn parse_replay_options() {
+        let args = |a: &[&str]| a.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            ReplayOptions::parse(&args(&["--dry-run", "a.jsonl", "b.jsonl"])).unwrap(),
+            ReplayOptions {
+                realtime: false,
+                dry_run: true,
+                files: vec![String::from("a.jsonl"), String::from("b.jsonl")],
+            }
+        );
+        assert!(
+            ReplayOptions::parse(&args(&["--realtime", "a.jsonl"]))
+                .unwrap()
+                .realtime
+        );
+        assert!(ReplayOptions::parse(&args(&["--dry-run"])).is_err());
+        assert!(ReplayOptions::parse(&args(&["--fast", "a.jsonl"])).is_err());
+    }
+
+    #[async_std::test]
+    async fn replay_captured_session() {
+        let directory = std::env::temp_dir().join(format!(
+            "stratum-observer-test-replay-{}",
+            std::process::id()
+        ));
+        let mut writer = CaptureWriter::new(
+            CaptureConfig {
+                directory: directory.to_string_lossy().to_string(),
+                ..Default::default()
+            },
+            "Test Pool",
+        );
+        let connected = Utc::now() - chrono::TimeDelta::seconds(60);
+        let lines = [
+            (
+                Direction::Out,
+                r#"{"id":0,"method":"mining.configure","params":[["version-rolling"],{"version-rolling.mask":"1fffe000","version-rolling.min-bit-count":2}]}"#,
+            ),
+            (
+                Direction::In,
+                r#"{"id":0,"result":{"version-rolling":true,"version-rolling.mask":"1fffe000"},"error":null}"#,
+            ),
+            (
+                Direction::Out,
+                r#"{"id":1,"method":"mining.subscribe","params":["stratum-observer"]}"#,
+            ),
+            (
+                Direction::In,
+                r#"{"id":1,"result":[[["mining.notify","ae6812eb4cd7735a302a8a9dd95cf71f"]],"08000002",4],"error":null}"#,
+            ),
+            (
+                Direction::Out,
+                r#"{"id":2,"method":"mining.extranonce.subscribe","params":[]}"#,
+            ),
+            (
+                Direction::Out,
+                r#"{"id":3,"method":"mining.authorize","params":["observer",""]}"#,
+            ),
+            (Direction::In, r#"{"id":3,"result":true,"error":null}"#),
+            (
+                Direction::In,
+                r#"{"params":["bf","4d16b6f85af6e2198f44ae2a6de67f78487ae5611b77c6c0440b921e00000000","01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff20020862062f503253482f04b8864e5008","072f736c7573682f000000000100f2052a010000001976a914d23fcdf86f7e756a64a7a9688ef9903327048ed988ac00000000",[],"00000002","1c2ac4af","504e86b9",false],"id":null,"method":"mining.notify"}"#,
+            ),
+        ];
+        for (i, (direction, line)) in lines.iter().enumerate() {
+            let record = CaptureRecord::new(
+                "Test Pool",
+                "127.0.0.1:3333",
+                "0123456789abcdef",
+                *direction,
+                line,
+                connected + chrono::TimeDelta::milliseconds(i as i64),
+            );
+            writer.write(&record).unwrap();
+        }
+        let files: Vec<String> = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path().to_string_lossy().to_string())
+            .collect();
+
+        let (update_sender, updates) = unbounded();
+        let options = ReplayOptions {
+            realtime: false,
+            dry_run: true,
+            files,
+        };
+        let result = replay(&options, &[], update_sender).await;
+        std::fs::remove_dir_all(&directory).unwrap();
+        result.unwrap();
+
+        let updates: Vec<Update> = std::iter::from_fn(|| updates.try_recv().ok()).collect();
+        let jobs: Vec<_> = updates
+            .into_iter()
+            .filter_map(|u| match u {
+                Update::Job(job) => Some(job),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].pool.name, "Test Pool");
+        assert_eq!(jobs[0].time_connected, connected);
+        assert_eq!(
+            jobs[0].timestamp,
+            connected + chrono::TimeDelta::milliseconds(7)
+        );
+        assert_eq!(jobs[0].extranonce1.as_ref(), &[0x08, 0x00, 0x00, 0x02]);
+        assert_eq!(jobs[0].version_rolling_mask, Some(0x1fffe000));
+    }
+}
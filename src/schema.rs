@@ -18,5 +18,83 @@ diesel::table! {
         coinbase_output_count -> Int4,
         coinbase_tag -> Text,
         coinbase_height -> Int8,
+        protocol -> Text,
+        difficulty -> Nullable<Float8>,
+        version_rolling_mask -> Nullable<Int8>,
+        endpoint -> Text,
+        identified_pool -> Nullable<Text>,
+        identified_by -> Nullable<Text>,
+        pool_mismatch -> Bool,
+        witness_commitment -> Nullable<Bytea>,
+        witness_commitment_changed -> Bool,
     }
 }
+
+diesel::table! {
+    coinbase_outputs (id) {
+        id -> Int4,
+        job_update_id -> Int4,
+        output_index -> Int4,
+        value -> Int8,
+        script_type -> Text,
+        address -> Nullable<Text>,
+        script -> Bytea,
+    }
+}
+
+diesel::table! {
+    difficulty_updates (id) {
+        id -> Int4,
+        timestamp -> Timestamp,
+        pool -> Text,
+        difficulty -> Float8,
+        previous_difficulty -> Nullable<Float8>,
+    }
+}
+
+diesel::table! {
+    extranonce_updates (id) {
+        id -> Int4,
+        timestamp -> Timestamp,
+        pool -> Text,
+        extranonce1 -> Bytea,
+        extranonce2_size -> Int4,
+        previous_extranonce1 -> Bytea,
+        previous_extranonce2_size -> Int4,
+    }
+}
+
+diesel::table! {
+    tls_updates (id) {
+        id -> Int4,
+        timestamp -> Timestamp,
+        pool -> Text,
+        protocol_version -> Text,
+        cipher_suite -> Text,
+        certificate_fingerprint -> Text,
+    }
+}
+
+diesel::table! {
+    connection_events (id) {
+        id -> Int4,
+        timestamp -> Timestamp,
+        pool -> Text,
+        endpoint -> Text,
+        kind -> Text,
+        address -> Nullable<Text>,
+        message -> Nullable<Text>,
+        latency_ms -> Nullable<Float8>,
+    }
+}
+
+diesel::joinable!(coinbase_outputs -> job_updates (job_update_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    coinbase_outputs,
+    connection_events,
+    difficulty_updates,
+    extranonce_updates,
+    job_updates,
+    tls_updates,
+);
@@ -0,0 +1,134 @@
+//! Graceful shutdown on SIGINT and SIGTERM: the pool connections are closed, which closes
+//! the update channel, and the queued updates are written to the database and websocket
+//! before the process exits.
+
+use async_channel::{bounded, Receiver, Sender};
+use async_std::task;
+use futures::future::{select, Either};
+use futures::{Future, StreamExt};
+use log::{error, info, warn};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook_async_std::Signals;
+use std::pin::pin;
+use std::process::exit;
+use std::time::Duration;
+
+/// The default time in seconds to shut down gracefully before exiting anyway.
+pub const DEFAULT_SHUTDOWN_TIMEOUT_SECONDS: f64 = 10.0;
+
+/// Tells the tasks holding a clone to shut down.
+#[derive(Debug, Clone)]
+pub struct ShutdownSignal {
+    sender: Sender<()>,
+    receiver: Receiver<()>,
+}
+
+impl Default for ShutdownSignal {
+    fn default() -> Self {
+        let (sender, receiver) = bounded(1);
+        ShutdownSignal { sender, receiver }
+    }
+}
+
+impl ShutdownSignal {
+    pub fn trigger(&self) {
+        self.sender.close();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    /// Waits until the shutdown is triggered.
+    pub async fn wait(&self) {
+        let _ = self.receiver.recv().await;
+    }
+
+    /// Runs the future until it completes or the shutdown is triggered. Returns None if the
+    /// shutdown was triggered first.
+    pub async fn unless_triggered<F: Future>(&self, future: F) -> Option<F::Output> {
+        let future = pin!(future);
+        let wait = pin!(self.wait());
+        match select(future, wait).await {
+            Either::Left((output, _)) => Some(output),
+            Either::Right(_) => None,
+        }
+    }
+}
+
+/// Triggers the shutdown on the first SIGINT or SIGTERM. If the process didn't exit within
+/// the timeout or on a second signal, it exits right away.
+pub fn handle_signals(shutdown: ShutdownSignal, timeout: Duration) {
+    let mut signals = match Signals::new([SIGINT, SIGTERM]) {
+        Ok(signals) => signals,
+        Err(e) => {
+            error!("Could not register signal handlers: {}", e);
+            return;
+        }
+    };
+    task::spawn(async move {
+        if signals.next().await.is_none() {
+            return;
+        }
+        info!(
+            "Shutting down: closing the pool connections and writing the queued updates (timeout {:.1}s)",
+            timeout.as_secs_f64()
+        );
+        shutdown.trigger();
+        task::spawn(async move {
+            task::sleep(timeout).await;
+            warn!(
+                "Could not shut down within {:.1}s: exiting",
+                timeout.as_secs_f64()
+            );
+            exit(1)
+        });
+        if signals.next().await.is_some() {
+            warn!("Received a second signal: exiting");
+            exit(1)
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::future;
+
+    #[async_std::test]
+    async fn trigger_wakes_all_waiting_tasks() {
+        let shutdown = ShutdownSignal::default();
+        let waiting: Vec<_> = (0..3)
+            .map(|_| {
+                let shutdown = shutdown.clone();
+                task::spawn(async move { shutdown.wait().await })
+            })
+            .collect();
+        assert!(!shutdown.is_triggered());
+        assert!(future::timeout(Duration::from_millis(50), shutdown.wait())
+            .await
+            .is_err());
+        shutdown.trigger();
+        assert!(shutdown.is_triggered());
+        for task in waiting {
+            future::timeout(Duration::from_secs(1), task)
+                .await
+                .expect("waiting tasks should be woken");
+        }
+    }
+
+    #[async_std::test]
+    async fn abort_future_when_triggered() {
+        let shutdown = ShutdownSignal::default();
+        assert_eq!(shutdown.unless_triggered(async { 1 }).await, Some(1));
+        let waiting = {
+            let shutdown = shutdown.clone();
+            task::spawn(async move { shutdown.unless_triggered(future::pending::<()>()).await })
+        };
+        shutdown.trigger();
+        let output = future::timeout(Duration::from_secs(1), waiting)
+            .await
+            .expect("the future should be aborted");
+        assert_eq!(output, None);
+    }
+}
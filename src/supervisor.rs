@@ -0,0 +1,446 @@
+//! Starts and stops the pool tasks. On a configuration reload, only the tasks of pools that
+//! were added, removed or changed are started or stopped. Pools can be disabled, enabled and
+//! reconnected with the admin API.
+
+use crate::capture::{self, CaptureConfig};
+use crate::client::Client;
+use crate::config::Config;
+use crate::connection::{Backoff, ConnectionState, PoolStatuses, Rotator};
+use crate::shutdown::ShutdownSignal;
+use crate::sv2_client::Sv2Client;
+use crate::types::{Pool, Protocol, Update};
+use async_channel::Sender;
+use async_std::{future, task};
+use futures::future::{select, Either};
+use log::{debug, info, warn};
+use std::collections::BTreeMap;
+use std::pin::pin;
+use std::time::Duration;
+
+/// The time a pool task has to stop before the supervisor cancels it, so that a single pool
+/// can't hold up reloads and admin requests.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The settings a pool task is started with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolSettings {
+    pub pool: Pool,
+    pub backoff: Backoff,
+    pub rejected_backoff: Backoff,
+    /// Where the pool's stratum lines are written, if they are captured.
+    pub capture: Option<CaptureConfig>,
+}
+
+impl PoolSettings {
+    /// The settings of the configured pools.
+    pub fn from_config(config: &Config) -> Vec<PoolSettings> {
+        config
+            .pools
+            .iter()
+            .map(|pool| PoolSettings {
+                pool: pool.clone(),
+                backoff: pool.backoff(&config.backoff),
+                rejected_backoff: pool.rejected_backoff(&config.rejected_backoff),
+                capture: match (pool.capture, pool.protocol) {
+                    (true, Protocol::Sv1) => Some(config.capture.clone()),
+                    (true, Protocol::Sv2) => {
+                        warn!(
+                            "Pool '{}': capture is only supported for Stratum v1",
+                            pool.name
+                        );
+                        None
+                    }
+                    (false, _) => None,
+                },
+            })
+            .collect()
+    }
+}
+
+/// The pool tasks to stop and start to go from the running to the new settings. Changed
+/// pools are stopped and started again.
+#[derive(Debug, PartialEq)]
+struct PoolChanges {
+    stop: Vec<String>,
+    start: Vec<PoolSettings>,
+}
+
+fn diff<'a>(running: impl Iterator<Item = &'a PoolSettings>, new: &[PoolSettings]) -> PoolChanges {
+    let running: BTreeMap<&str, &PoolSettings> =
+        running.map(|s| (s.pool.name.as_str(), s)).collect();
+    let stop = running
+        .iter()
+        .filter(|(_, settings)| !new.contains(settings))
+        .map(|(name, _)| name.to_string())
+        .collect();
+    let start = new
+        .iter()
+        .filter(|settings| running.get(settings.pool.name.as_str()) != Some(settings))
+        .cloned()
+        .collect();
+    PoolChanges { stop, start }
+}
+
+struct PoolTask {
+    settings: PoolSettings,
+    stop: ShutdownSignal,
+    handle: task::JoinHandle<()>,
+}
+
+/// An action on a pool task requested with the admin API.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PoolAction {
+    /// Starts the task of a disabled pool.
+    Enable,
+    /// Stops the pool's task until it's enabled again or the observer is restarted.
+    Disable,
+    /// Closes the pool's connection and opens a new one right away.
+    Reconnect,
+}
+
+/// Owns the running pool tasks.
+pub struct Supervisor {
+    tasks: BTreeMap<String, PoolTask>,
+    /// The settings of the configured pools that are disabled.
+    disabled: BTreeMap<String, PoolSettings>,
+    update_sender: Sender<Update<'static>>,
+    statuses: PoolStatuses,
+}
+
+impl Supervisor {
+    pub fn new(update_sender: Sender<Update<'static>>, statuses: PoolStatuses) -> Self {
+        Supervisor {
+            tasks: BTreeMap::new(),
+            disabled: BTreeMap::new(),
+            update_sender,
+            statuses,
+        }
+    }
+
+    /// Starts, stops and restarts the pool tasks so that they match the configuration.
+    /// Disabled pools stay disabled, but use the new settings once enabled.
+    pub async fn apply(&mut self, config: &Config) {
+        let settings = PoolSettings::from_config(config);
+        let statuses = &self.statuses;
+        self.disabled.retain(|name, _| {
+            let configured = settings.iter().any(|s| &s.pool.name == name);
+            if !configured {
+                statuses.remove(name);
+            }
+            configured
+        });
+        let changes = diff(self.tasks.values().map(|t| &t.settings), &settings);
+        for name in changes.stop {
+            self.stop_task(&name).await;
+            self.statuses.remove(&name);
+        }
+        for settings in changes.start {
+            if let Some(disabled) = self.disabled.get_mut(&settings.pool.name) {
+                *disabled = settings;
+            } else {
+                self.start_task(settings);
+            }
+        }
+    }
+
+    /// The names of the configured pools and whether they are enabled.
+    pub fn pools(&self) -> Vec<(String, bool)> {
+        let mut pools: Vec<(String, bool)> = self
+            .tasks
+            .keys()
+            .map(|name| (name.clone(), true))
+            .chain(self.disabled.keys().map(|name| (name.clone(), false)))
+            .collect();
+        pools.sort();
+        pools
+    }
+
+    /// Enables, disables or reconnects a pool. Returns false if there's no such pool.
+    pub async fn control(&mut self, name: &str, action: PoolAction) -> bool {
+        match action {
+            PoolAction::Enable => {
+                if let Some(settings) = self.disabled.remove(name) {
+                    info!("Enabling pool '{}'", name);
+                    self.statuses.remove(name);
+                    self.start_task(settings);
+                }
+            }
+            PoolAction::Disable => {
+                if let Some(settings) = self.stop_task(name).await {
+                    info!("Disabling pool '{}'", name);
+                    self.statuses.set_state(name, ConnectionState::Disabled);
+                    self.disabled.insert(name.to_string(), settings);
+                }
+            }
+            PoolAction::Reconnect => {
+                if let Some(settings) = self.stop_task(name).await {
+                    info!("Reconnecting to pool '{}'", name);
+                    self.start_task(settings);
+                }
+            }
+        }
+        self.tasks.contains_key(name) || self.disabled.contains_key(name)
+    }
+
+    fn start_task(&mut self, settings: PoolSettings) {
+        let stop = ShutdownSignal::default();
+        let name = settings.pool.name.clone();
+        let handle = spawn_pool_task(
+            settings.clone(),
+            self.update_sender.clone(),
+            self.statuses.clone(),
+            stop.clone(),
+        );
+        self.tasks.insert(
+            name,
+            PoolTask {
+                settings,
+                stop,
+                handle,
+            },
+        );
+    }
+
+    /// Stops the pool's task and waits until it stopped. A task that doesn't stop within
+    /// STOP_TIMEOUT is cancelled. Returns its settings if it was running.
+    async fn stop_task(&mut self, name: &str) -> Option<PoolSettings> {
+        let mut task = self.tasks.remove(name)?;
+        info!("Stopping task for pool: '{}'", name);
+        task.stop.trigger();
+        if future::timeout(STOP_TIMEOUT, &mut task.handle)
+            .await
+            .is_err()
+        {
+            warn!(
+                "Task for pool '{}' did not stop within {}s: cancelling it",
+                name,
+                STOP_TIMEOUT.as_secs()
+            );
+            task.handle.cancel().await;
+        }
+        Some(task.settings)
+    }
+
+    /// Stops all pool tasks. Once their clients closed the connections, the update channel
+    /// is closed.
+    pub async fn stop(self) {
+        for task in self.tasks.values() {
+            task.stop.trigger();
+        }
+        for (_, task) in self.tasks {
+            task.handle.await;
+        }
+    }
+}
+
+/// Spawns a task that connects to the pool and reconnects when the connection is closed,
+/// until it's stopped.
+fn spawn_pool_task(
+    settings: PoolSettings,
+    us: Sender<Update<'static>>,
+    statuses: PoolStatuses,
+    stop: ShutdownSignal,
+) -> task::JoinHandle<()> {
+    let PoolSettings {
+        pool,
+        backoff,
+        rejected_backoff,
+        capture,
+    } = settings;
+    let capture = capture.map(|config| capture::spawn_writer(config, &pool.name));
+    task::spawn(async move {
+        debug!("Spawned task for pool: '{}'", pool.name);
+        let mut rotator = Rotator::default();
+        // reopen the connection when a client or we close the connection. Stratum v1
+        // connections that reached their max_lifetime are replaced before they're closed.
+        while !stop.is_triggered() {
+            statuses.set_state(&pool.name, ConnectionState::Connecting);
+            let endpoint = statuses.endpoint(&pool);
+            match pool.protocol {
+                Protocol::Sv1 => {
+                    let client = task::spawn({
+                        let (pool, us, statuses, capture, stop) = (
+                            pool.clone(),
+                            us.clone(),
+                            statuses.clone(),
+                            capture.clone(),
+                            stop.clone(),
+                        );
+                        let rotation = rotator.next_connection();
+                        async move {
+                            Client::run(&pool, &endpoint, us, statuses, capture, rotation, stop)
+                                .await
+                        }
+                    });
+                    let expired = match select(client, pin!(rotator.expired())).await {
+                        Either::Left(_) => {
+                            rotator.closed();
+                            false
+                        }
+                        Either::Right(_) => true,
+                    };
+                    if expired {
+                        info!(
+                            "Connection to pool '{}' reached its max_lifetime: opening a replacement",
+                            pool.name
+                        );
+                        rotator.rotate();
+                    }
+                }
+                Protocol::Sv2 => {
+                    Sv2Client::run(&pool, &endpoint, us.clone(), statuses.clone(), stop.clone())
+                        .await
+                }
+            }
+            if stop.is_triggered() {
+                break;
+            }
+            let delay = statuses.disconnected(&pool, &backoff, &rejected_backoff);
+            if !delay.is_zero() {
+                let (failures, rejections) = statuses
+                    .get(&pool.name)
+                    .map(|s| (s.failures, s.rejections))
+                    .unwrap_or_default();
+                info!(
+                    "Pool '{}' didn't go live in {} attempt(s) and rejected our credentials {} time(s) in a row: backing off for {:.1}s",
+                    pool.name,
+                    failures,
+                    rejections,
+                    delay.as_secs_f64()
+                );
+                let sleep = pin!(task::sleep(delay));
+                select(sleep, pin!(stop.wait())).await;
+            }
+        }
+        debug!("Stopped task for pool: '{}'", pool.name);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+    use crate::mock_pool::MockPool;
+    use crate::types::ConnectionEventKind;
+    use async_channel::{unbounded, Receiver};
+
+    fn config(pools: &[(&str, &str)]) -> Config {
+        let pools: Vec<String> = pools
+            .iter()
+            .map(|(name, endpoint)| {
+                format!(
+                    r#"{{ endpoint = "{}", name = "{}", user = "observer" }}"#,
+                    endpoint, name
+                )
+            })
+            .collect();
+        parse_config(&format!("pools = [{}]", pools.join(", "))).unwrap()
+    }
+
+    #[test]
+    fn diff_pool_settings() {
+        let running = PoolSettings::from_config(&config(&[
+            ("Kept", "127.0.0.1:3333"),
+            ("Removed", "127.0.0.1:3334"),
+            ("Changed", "127.0.0.1:3335"),
+        ]));
+        let new = PoolSettings::from_config(&config(&[
+            ("Changed", "127.0.0.1:4444"),
+            ("Kept", "127.0.0.1:3333"),
+            ("Added", "127.0.0.1:3336"),
+        ]));
+        let changes = diff(running.iter(), &new);
+        assert_eq!(changes.stop, vec!["Changed", "Removed"]);
+        assert_eq!(changes.start, vec![new[0].clone(), new[2].clone()]);
+
+        let changes = diff(new.iter(), &new);
+        assert!(changes.stop.is_empty());
+        assert!(changes.start.is_empty());
+    }
+
+    async fn next_event(updates: &Receiver<Update<'static>>, kind: ConnectionEventKind) {
+        loop {
+            let update = future::timeout(Duration::from_secs(5), updates.recv())
+                .await
+                .expect("the update should arrive in time")
+                .expect("the update channel should be open");
+            if let Update::Connection(event) = update {
+                if event.kind == kind {
+                    return;
+                }
+            }
+        }
+    }
+
+    #[async_std::test]
+    async fn apply_reloaded_config_to_pool_tasks() {
+        let kept = MockPool::bind().await;
+        let removed = MockPool::bind().await;
+        let (update_sender, updates) = unbounded();
+        let statuses = PoolStatuses::default();
+        let mut supervisor = Supervisor::new(update_sender, statuses.clone());
+        supervisor
+            .apply(&config(&[
+                ("Kept", &kept.endpoint()),
+                ("Removed", &removed.endpoint()),
+            ]))
+            .await;
+        let mut kept_connection = kept.accept().await;
+        kept_connection.handshake().await;
+        let mut removed_connection = removed.accept().await;
+        removed_connection.handshake().await;
+
+        supervisor
+            .apply(&config(&[("Kept", &kept.endpoint())]))
+            .await;
+        next_event(&updates, ConnectionEventKind::Shutdown).await;
+        removed_connection.expect_closed().await;
+        assert!(statuses.get("Removed").is_none());
+        assert!(statuses.get("Kept").is_some());
+        assert_eq!(supervisor.tasks.len(), 1);
+
+        supervisor.stop().await;
+        kept_connection.expect_closed().await;
+        next_event(&updates, ConnectionEventKind::Shutdown).await;
+        // all clients stopped and dropped their update senders
+        while updates.recv().await.is_ok() {}
+    }
+
+    #[async_std::test]
+    async fn disable_enable_and_reconnect_pool() {
+        let mock = MockPool::bind().await;
+        let (update_sender, updates) = unbounded();
+        let statuses = PoolStatuses::default();
+        let mut supervisor = Supervisor::new(update_sender, statuses.clone());
+        let config = config(&[("Pool", &mock.endpoint())]);
+        supervisor.apply(&config).await;
+        let mut connection = mock.accept().await;
+        connection.handshake().await;
+
+        assert!(supervisor.control("Pool", PoolAction::Reconnect).await);
+        connection.expect_closed().await;
+        let mut connection = mock.accept().await;
+        connection.handshake().await;
+
+        assert!(supervisor.control("Pool", PoolAction::Disable).await);
+        connection.expect_closed().await;
+        assert_eq!(
+            statuses.get("Pool").unwrap().state,
+            ConnectionState::Disabled
+        );
+        assert_eq!(supervisor.pools(), vec![(String::from("Pool"), false)]);
+        // a reload keeps the pool disabled
+        supervisor.apply(&config).await;
+        assert_eq!(supervisor.pools(), vec![(String::from("Pool"), false)]);
+
+        assert!(supervisor.control("Pool", PoolAction::Enable).await);
+        let mut connection = mock.accept().await;
+        connection.handshake().await;
+        assert_eq!(supervisor.pools(), vec![(String::from("Pool"), true)]);
+        assert!(!supervisor.control("Unknown", PoolAction::Disable).await);
+
+        supervisor.stop().await;
+        connection.expect_closed().await;
+        while updates.recv().await.is_ok() {}
+    }
+}
@@ -0,0 +1,1054 @@
+use crate::connection::{ConnectionState, PoolStatuses};
+use crate::shutdown::ShutdownSignal;
+use crate::transport::{self, Endpoint};
+use crate::types::{
+    CoinbaseInfo, ConnectionEvent, ConnectionEventKind, DifficultyUpdate, ExtranonceUpdate,
+    JobUpdate, Pool, Update,
+};
+use crate::utils::{encode_hex, target_to_difficulty, BIP320_VERSION_ROLLING_MASK};
+use async_channel::Sender;
+use async_std::{future, net::TcpStream, prelude::*};
+use binary_sv2::U256;
+use chrono::prelude::*;
+use codec_sv2::noise_sv2::INITIATOR_EXPECTED_HANDSHAKE_MESSAGE_SIZE;
+use codec_sv2::{
+    HandshakeRole, Initiator, NoiseEncoder, StandardEitherFrame, StandardNoiseDecoder,
+    StandardSv2Frame, State, Sv2Frame,
+};
+use futures::future::{select, Either};
+use key_utils::Secp256k1PublicKey;
+use log::{debug, error, info, warn};
+use roles_logic_sv2::common_messages_sv2::{Protocol, SetupConnection};
+use roles_logic_sv2::mining_sv2::OpenExtendedMiningChannel;
+use roles_logic_sv2::parsers::{CommonMessages, IsSv2Message, Mining, PoolMessages};
+use std::collections::BTreeMap;
+use std::pin::pin;
+use std::time::Instant;
+use std::{error, fmt, io};
+use sv1_api::server_to_client::Notify;
+use sv1_api::utils::{Extranonce, HexU32Be, MerkleNode, PrevHash};
+
+const SV2_PROTOCOL_VERSION: u16 = 2;
+/// The hash rate we announce when opening a mining channel. We never submit shares, but
+/// pools might refuse to open channels for very small hash rates.
+const NOMINAL_HASH_RATE: f32 = 1_000_000_000_000.0; // 1 TH/s
+/// The request id used when opening our (single) extended mining channel.
+const OPEN_CHANNEL_REQUEST_ID: u32 = 1;
+
+type Message = PoolMessages<'static>;
+
+/// An extended mining job as received in a NewExtendedMiningJob message.
+struct Sv2Job {
+    version: u32,
+    version_rolling_allowed: bool,
+    merkle_path: Vec<Vec<u8>>,
+    coinbase_prefix: Vec<u8>,
+    coinbase_suffix: Vec<u8>,
+}
+
+/// The previous block hash and nbits as received in a SetNewPrevHash message.
+struct Sv2PrevHash {
+    prev_hash: [u8; 32],
+    nbits: u32,
+}
+
+/// A Stratum v2 pool client. It opens a single extended mining channel and turns
+/// NewExtendedMiningJob and SetNewPrevHash messages into the same [JobUpdate]s the
+/// Stratum v1 [crate::client::Client] produces. Extended channels are used as standard
+/// channels only share the merkle root and not the coinbase transaction.
+pub struct Sv2Client {
+    pool: Pool,
+    endpoint: Endpoint,
+    update_sender: Sender<Update<'static>>,
+    statuses: PoolStatuses,
+    stream: TcpStream,
+    state: State,
+    encoder: NoiseEncoder<Message>,
+    decoder: StandardNoiseDecoder<Message>,
+    time_connected: DateTime<Utc>,
+    /// Receive time of the frame currently being handled.
+    time_received: DateTime<Utc>,
+    channel_id: Option<u32>,
+    extranonce_prefix: Vec<u8>,
+    extranonce_size: usize,
+    difficulty: Option<f64>,
+    future_jobs: BTreeMap<u32, Sv2Job>,
+    active_job: Option<(u32, Sv2Job)>,
+    prev_hash: Option<Sv2PrevHash>,
+}
+
+impl Sv2Client {
+    pub async fn run(
+        pool: &Pool,
+        endpoint: &str,
+        update_sender: Sender<Update<'static>>,
+        statuses: PoolStatuses,
+        shutdown_signal: ShutdownSignal,
+    ) {
+        let endpoint = match Endpoint::parse(endpoint) {
+            Ok(endpoint) => endpoint,
+            Err(e) => {
+                error!("Can't connect to pool '{}': {}", pool.name, e);
+                return;
+            }
+        };
+        let event = ConnectionEvent::new(
+            pool,
+            &endpoint.to_string(),
+            ConnectionEventKind::ConnectAttempt,
+        );
+        send_event(&update_sender, event);
+        let connect_start = Instant::now();
+        let stream = match shutdown_signal
+            .unless_triggered(transport::connect_tcp(pool, &endpoint))
+            .await
+        {
+            None => {
+                debug!("Stopped connecting to {} as its pool task stops", pool.name);
+                let event = ConnectionEvent::new(
+                    pool,
+                    &endpoint.to_string(),
+                    ConnectionEventKind::Shutdown,
+                );
+                send_event(&update_sender, event);
+                return;
+            }
+            Some(Ok(st)) => st,
+            Some(Err(e)) => {
+                warn!("Pool '{}' unreachable: {}", pool.name, e);
+                let event = ConnectionEvent::failed(pool, &endpoint.to_string(), &e);
+                send_event(&update_sender, event);
+                return;
+            }
+        };
+        let address = transport::peer_address(pool, &stream);
+        info!(
+            "Connected to Stratum v2 pool '{}' at {} ({})",
+            pool.name,
+            endpoint,
+            address
+                .map(|a| a.ip().to_string())
+                .unwrap_or_else(|| String::from("via proxy"))
+        );
+        let event = ConnectionEvent {
+            address,
+            latency: Some(connect_start.elapsed()),
+            ..ConnectionEvent::new(pool, &endpoint.to_string(), ConnectionEventKind::Connected)
+        };
+        send_event(&update_sender, event);
+        statuses.set_state(&pool.name, ConnectionState::Handshaking);
+
+        // A pool that accepts the connection but never completes the handshake or setup must
+        // not stall the pool task, so both are bounded by the job timeout.
+        let establish = async {
+            let mut client = Sv2Client::handshake(
+                pool,
+                endpoint.clone(),
+                update_sender.clone(),
+                statuses.clone(),
+                stream,
+            )
+            .await
+            .inspect_err(|e| warn!("Noise handshake with pool '{}' failed: {}", pool.name, e))?;
+            client.setup().await.inspect_err(|e| {
+                warn!(
+                    "Could not set up a mining channel with pool '{}': {}",
+                    pool.name, e
+                )
+            })?;
+            Ok::<_, Sv2ClientError>(client)
+        };
+        let established = shutdown_signal
+            .unless_triggered(future::timeout(pool.job_timeout(), establish))
+            .await;
+        let mut client = match established {
+            Some(Ok(Ok(client))) => client,
+            Some(Ok(Err(e))) => {
+                let event = ConnectionEvent::new(pool, &endpoint.to_string(), e.event_kind())
+                    .with_message(e.to_string());
+                send_event(&update_sender, event);
+                if let Sv2ClientError::Rejected(message) = e {
+                    statuses.rejected(&pool.name, message);
+                }
+                return;
+            }
+            Some(Err(_)) => {
+                warn!(
+                    "Pool '{}' did not complete the connection setup in {}s: disconnecting...",
+                    pool.name,
+                    pool.job_timeout().as_secs()
+                );
+                let event = ConnectionEvent::new(
+                    pool,
+                    &endpoint.to_string(),
+                    ConnectionEventKind::JobTimeout,
+                )
+                .with_message(String::from("connection setup timed out"));
+                send_event(&update_sender, event);
+                return;
+            }
+            None => {
+                debug!("Closing connection to {} as its pool task stops", pool.name);
+                let event = ConnectionEvent::new(
+                    pool,
+                    &endpoint.to_string(),
+                    ConnectionEventKind::Shutdown,
+                );
+                send_event(&update_sender, event);
+                return;
+            }
+        };
+
+        if let Err(e) = client.process_messages(&shutdown_signal).await {
+            warn!("Connection to pool '{}' closed: {}", pool.name, e);
+            client.closed_with_error(&e);
+        }
+        client.shutdown();
+    }
+
+    async fn handshake(
+        pool: &Pool,
+        endpoint: Endpoint,
+        update_sender: Sender<Update<'static>>,
+        statuses: PoolStatuses,
+        mut stream: TcpStream,
+    ) -> Result<Sv2Client, Sv2ClientError> {
+        let initiator = match &pool.authority_pubkey {
+            Some(key) => {
+                let key: Secp256k1PublicKey = key
+                    .parse()
+                    .map_err(|e| Sv2ClientError::InvalidAuthorityPubkey(format!("{}", e)))?;
+                Initiator::from_raw_k(key.into_bytes())?
+            }
+            None => Initiator::without_pk()?,
+        };
+        let mut state = State::initialized(HandshakeRole::Initiator(initiator));
+
+        // The handshake messages are send unframed.
+        let first_message = state.step_0()?;
+        stream
+            .write_all(&first_message.get_payload_when_handshaking())
+            .await?;
+        let mut second_message = [0u8; INITIATOR_EXPECTED_HANDSHAKE_MESSAGE_SIZE];
+        stream.read_exact(&mut second_message).await?;
+        let state = state.step_2(second_message)?;
+        debug!("Completed Noise handshake with '{}'", pool.name);
+
+        Ok(Sv2Client {
+            pool: pool.clone(),
+            endpoint,
+            update_sender,
+            statuses,
+            stream,
+            state,
+            encoder: NoiseEncoder::new(),
+            decoder: StandardNoiseDecoder::new(),
+            time_connected: Utc::now(),
+            time_received: Utc::now(),
+            channel_id: None,
+            extranonce_prefix: vec![],
+            extranonce_size: 0,
+            difficulty: None,
+            future_jobs: BTreeMap::new(),
+            active_job: None,
+            prev_hash: None,
+        })
+    }
+
+    /// Sends a SetupConnection and opens an extended mining channel.
+    async fn setup(&mut self) -> Result<(), Sv2ClientError> {
+        let setup_connection = SetupConnection {
+            protocol: Protocol::MiningProtocol,
+            min_version: SV2_PROTOCOL_VERSION,
+            max_version: SV2_PROTOCOL_VERSION,
+            flags: 0,
+            endpoint_host: str0255(self.endpoint.host.clone())?,
+            endpoint_port: self.endpoint.port,
+            vendor: str0255(self.pool.user_agent())?,
+            hardware_version: str0255(String::new())?,
+            firmware: str0255(String::new())?,
+            device_id: str0255(String::new())?,
+        };
+        self.send_message(PoolMessages::Common(CommonMessages::SetupConnection(
+            setup_connection,
+        )))
+        .await?;
+
+        let mut frame = self.read_frame().await?;
+        match parse_frame(&mut frame)? {
+            PoolMessages::Common(CommonMessages::SetupConnectionSuccess(m)) => {
+                debug!(
+                    "SetupConnection with '{}' succeeded: used_version={} flags={:b}",
+                    self.pool.name, m.used_version, m.flags
+                );
+            }
+            PoolMessages::Common(CommonMessages::SetupConnectionError(m)) => {
+                return Err(Sv2ClientError::Rejected(format!(
+                    "SetupConnection error: {}",
+                    String::from_utf8_lossy(m.error_code.inner_as_ref())
+                )));
+            }
+            m => return Err(Sv2ClientError::UnexpectedMessage(m.message_type())),
+        }
+
+        let open_channel = OpenExtendedMiningChannel {
+            request_id: OPEN_CHANNEL_REQUEST_ID,
+            user_identity: str0255(self.pool.user.clone())?,
+            nominal_hash_rate: NOMINAL_HASH_RATE,
+            max_target: U256::from([0xffu8; 32]),
+            min_extranonce_size: 0,
+        };
+        self.send_message(PoolMessages::Mining(Mining::OpenExtendedMiningChannel(
+            open_channel,
+        )))
+        .await?;
+        Ok(())
+    }
+
+    /// Reads and handles messages until the connection is closed, too old, no
+    /// new job has been received in a while, or the pool task stops.
+    async fn process_messages(
+        &mut self,
+        shutdown_signal: &ShutdownSignal,
+    ) -> Result<(), Sv2ClientError> {
+        let max_lifetime = self.pool.max_lifetime();
+        let job_timeout = self.pool.job_timeout();
+        let connected = Instant::now();
+        let mut time_last_job = Instant::now();
+
+        loop {
+            let until_max_lifetime = max_lifetime.saturating_sub(connected.elapsed());
+            let until_job_timeout = job_timeout.saturating_sub(time_last_job.elapsed());
+            let input = async {
+                let frame = pin!(self.read_frame());
+                let shutdown = pin!(shutdown_signal.wait());
+                match select(frame, shutdown).await {
+                    Either::Left((frame, _)) => Some(frame),
+                    Either::Right(_) => None,
+                }
+            };
+            let mut frame = match future::timeout(until_max_lifetime.min(until_job_timeout), input)
+                .await
+            {
+                Ok(Some(frame)) => frame?,
+                Ok(None) => {
+                    debug!(
+                        "Closing connection to {} as its pool task stops",
+                        self.pool.name
+                    );
+                    self.send_connection_event(ConnectionEventKind::Shutdown, None);
+                    return Ok(());
+                }
+                Err(_) => {
+                    if connected.elapsed() >= max_lifetime {
+                        debug!(
+                            "Closing connection to {} as the connection is {:?} old (max_lifetime={}s)",
+                            self.pool.name,
+                            connected.elapsed(),
+                            max_lifetime.as_secs(),
+                        );
+                        self.send_connection_event(ConnectionEventKind::MaxLifetime, None);
+                    } else {
+                        warn!(
+                            "No job from {} in more than {}s: disconnecting...",
+                            self.pool.name,
+                            job_timeout.as_secs()
+                        );
+                        self.send_connection_event(ConnectionEventKind::JobTimeout, None);
+                    }
+                    return Ok(());
+                }
+            };
+
+            self.time_received = Utc::now();
+            match parse_frame(&mut frame)? {
+                PoolMessages::Mining(m) => {
+                    if self.handle_mining_message(m)? {
+                        time_last_job = Instant::now();
+                        self.statuses
+                            .set_state(&self.pool.name, ConnectionState::Live);
+                    }
+                }
+                m => debug!(
+                    "Ignoring message with type {:#04x} from '{}'",
+                    m.message_type(),
+                    self.pool.name
+                ),
+            }
+        }
+    }
+
+    /// Handles a message of the mining protocol. Returns true if a new job was
+    /// published.
+    fn handle_mining_message(&mut self, message: Mining) -> Result<bool, Sv2ClientError> {
+        match message {
+            Mining::OpenExtendedMiningChannelSuccess(m) => {
+                debug!(
+                    "Opened extended mining channel {} with '{}': extranonce_prefix={:?} extranonce_size={}",
+                    m.channel_id, self.pool.name, m.extranonce_prefix, m.extranonce_size
+                );
+                self.channel_id = Some(m.channel_id);
+                self.send_connection_event(ConnectionEventKind::Subscribed, None);
+                self.extranonce_prefix = m.extranonce_prefix.to_vec();
+                self.extranonce_size = m.extranonce_size as usize;
+                self.update_difficulty(m.target.inner_as_ref());
+            }
+            Mining::SetTarget(m) => {
+                self.update_difficulty(m.maximum_target.inner_as_ref());
+            }
+            Mining::OpenMiningChannelError(m) => {
+                return Err(Sv2ClientError::Rejected(format!(
+                    "OpenMiningChannel error: {}",
+                    String::from_utf8_lossy(m.error_code.inner_as_ref())
+                )));
+            }
+            Mining::SetExtranoncePrefix(m) => {
+                let extranonce_prefix = m.extranonce_prefix.to_vec();
+                info!(
+                    "Pool '{}' changed extranonce_prefix from {} to {}",
+                    self.pool.name,
+                    encode_hex(&self.extranonce_prefix),
+                    encode_hex(&extranonce_prefix),
+                );
+                let extranonce_update = ExtranonceUpdate {
+                    timestamp: self.time_received,
+                    pool: self.pool.clone(),
+                    extranonce1: extranonce_prefix.clone(),
+                    extranonce2_size: self.extranonce_size,
+                    previous_extranonce1: self.extranonce_prefix.clone(),
+                    previous_extranonce2_size: self.extranonce_size,
+                };
+                self.extranonce_prefix = extranonce_prefix;
+                if let Err(e) = self
+                    .update_sender
+                    .try_send(Update::Extranonce(extranonce_update))
+                {
+                    error!(
+                        "Failed to send ExtranonceUpdate for {}: {}",
+                        self.pool.name, e
+                    );
+                }
+            }
+            Mining::NewExtendedMiningJob(m) => {
+                let job = Sv2Job {
+                    version: m.version,
+                    version_rolling_allowed: m.version_rolling_allowed,
+                    merkle_path: m.merkle_path.to_vec(),
+                    coinbase_prefix: m.coinbase_tx_prefix.to_vec(),
+                    coinbase_suffix: m.coinbase_tx_suffix.to_vec(),
+                };
+                match m.min_ntime.into_inner() {
+                    // A future job: only becomes active with a SetNewPrevHash.
+                    None => {
+                        self.future_jobs.insert(m.job_id, job);
+                    }
+                    Some(min_ntime) => {
+                        self.publish_job(m.job_id, &job, min_ntime, false);
+                        self.active_job = Some((m.job_id, job));
+                        return Ok(true);
+                    }
+                }
+            }
+            Mining::SetNewPrevHash(m) => {
+                let prev_hash: [u8; 32] = m
+                    .prev_hash
+                    .inner_as_ref()
+                    .try_into()
+                    .expect("prev_hash should always be 32 byte");
+                self.prev_hash = Some(Sv2PrevHash {
+                    prev_hash,
+                    nbits: m.nbits,
+                });
+                match self.future_jobs.remove(&m.job_id) {
+                    Some(job) => {
+                        // Future jobs not referenced by this SetNewPrevHash are stale now.
+                        self.future_jobs.clear();
+                        self.publish_job(m.job_id, &job, m.min_ntime, true);
+                        self.active_job = Some((m.job_id, job));
+                        return Ok(true);
+                    }
+                    None => warn!(
+                        "Received SetNewPrevHash from '{}' for unknown job {}",
+                        self.pool.name, m.job_id
+                    ),
+                }
+            }
+            Mining::CloseChannel(m) => {
+                return Err(Sv2ClientError::Rejected(format!(
+                    "channel {} closed by pool: {}",
+                    m.channel_id,
+                    String::from_utf8_lossy(m.reason_code.inner_as_ref())
+                )));
+            }
+            m => debug!(
+                "Ignoring mining message with type {:#04x} from '{}'",
+                m.message_type(),
+                self.pool.name
+            ),
+        }
+        Ok(false)
+    }
+
+    /// Updates the difficulty from a new channel target and records the change.
+    fn update_difficulty(&mut self, target: &[u8]) {
+        let target: [u8; 32] = target.try_into().expect("target should always be 32 byte");
+        let difficulty = target_to_difficulty(&target);
+        if self.difficulty != Some(difficulty) {
+            debug!(
+                "Pool '{}' changed difficulty from {:?} to {}",
+                self.pool.name, self.difficulty, difficulty
+            );
+            let difficulty_update = DifficultyUpdate {
+                timestamp: self.time_received,
+                pool: self.pool.clone(),
+                difficulty,
+                previous_difficulty: self.difficulty,
+            };
+            if let Err(e) = self
+                .update_sender
+                .try_send(Update::Difficulty(difficulty_update))
+            {
+                error!(
+                    "Failed to send DifficultyUpdate for {}: {}",
+                    self.pool.name, e
+                );
+            }
+        }
+        self.difficulty = Some(difficulty);
+    }
+
+    fn publish_job(&self, job_id: u32, job: &Sv2Job, time: u32, clean_jobs: bool) {
+        let prev_hash = match &self.prev_hash {
+            Some(p) => p,
+            None => {
+                debug!(
+                    "Not publishing job {} by '{}' as we don't know the previous block hash yet",
+                    job_id, self.pool.name
+                );
+                return;
+            }
+        };
+        let notify = Notify {
+            job_id: job_id.to_string(),
+            prev_hash: PrevHash(U256::from(prev_hash.prev_hash)),
+            coin_base1: job.coinbase_prefix.clone().into(),
+            coin_base2: job.coinbase_suffix.clone().into(),
+            merkle_branch: job
+                .merkle_path
+                .iter()
+                .map(|node| U256::try_from(node.clone()).map(MerkleNode))
+                .collect::<Result<_, _>>()
+                .expect("merkle path nodes should always be 32 byte"),
+            version: HexU32Be(job.version),
+            bits: HexU32Be(prev_hash.nbits),
+            time: HexU32Be(time),
+            clean_jobs,
+        };
+        let extranonce1 = match Extranonce::try_from(self.extranonce_prefix.clone()) {
+            Ok(e) => e,
+            Err(e) => {
+                error!(
+                    "Invalid extranonce_prefix from '{}': {:?}",
+                    self.pool.name, e
+                );
+                return;
+            }
+        };
+        let coinbase_info = Box::new(CoinbaseInfo::decode(
+            &notify,
+            &extranonce1,
+            self.extranonce_size,
+            self.pool.network(),
+        ));
+        let job_update = JobUpdate {
+            timestamp: self.time_received,
+            pool: self.pool.clone(),
+            endpoint: self.endpoint.to_string(),
+            job: notify,
+            extranonce1,
+            extranonce2_size: self.extranonce_size,
+            difficulty: self.difficulty,
+            // Stratum v2 allows rolling the BIP320 general purpose bits if
+            // version_rolling_allowed is set.
+            version_rolling_mask: job
+                .version_rolling_allowed
+                .then_some(BIP320_VERSION_ROLLING_MASK),
+            time_connected: self.time_connected,
+            coinbase_info,
+            identification: None,
+            witness_commitment_changed: false,
+        };
+        if let Err(e) = self.update_sender.try_send(Update::Job(job_update)) {
+            error!("Failed to send JobUpdate for {}: {}", self.pool.name, e);
+        }
+    }
+
+    async fn send_message(&mut self, message: Message) -> Result<(), Sv2ClientError> {
+        debug!("sending to {}: {:?}", self.pool.name, message);
+        let message_type = message.message_type();
+        let channel_bit = message.channel_bit();
+        let frame = Sv2Frame::from_message(message, message_type, 0, channel_bit)
+            .ok_or(Sv2ClientError::UnexpectedMessage(message_type))?;
+        let encoded = self
+            .encoder
+            .encode(StandardEitherFrame::Sv2(frame), &mut self.state)?;
+        self.stream.write_all(encoded.as_ref()).await?;
+        Ok(())
+    }
+
+    async fn read_frame(&mut self) -> Result<StandardSv2Frame<Message>, Sv2ClientError> {
+        loop {
+            let buffer = self.decoder.writable();
+            self.stream.read_exact(buffer).await?;
+            match self.decoder.next_frame(&mut self.state) {
+                Ok(frame) => {
+                    return frame
+                        .try_into()
+                        .map_err(|_| Sv2ClientError::UnexpectedHandshakeFrame)
+                }
+                Err(codec_sv2::Error::MissingBytes(_)) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn send_event(&self, event: ConnectionEvent) {
+        send_event(&self.update_sender, event);
+    }
+
+    fn send_connection_event(&self, kind: ConnectionEventKind, message: Option<String>) {
+        self.send_event(ConnectionEvent {
+            message,
+            ..ConnectionEvent::new(&self.pool, &self.endpoint.to_string(), kind)
+        });
+    }
+
+    /// Records why the connection ended. Rejections are kept in the pool's status.
+    fn closed_with_error(&self, e: &Sv2ClientError) {
+        self.send_connection_event(e.event_kind(), Some(e.to_string()));
+        if let Sv2ClientError::Rejected(message) = e {
+            self.statuses.rejected(&self.pool.name, message.clone());
+        }
+    }
+
+    fn shutdown(&mut self) {
+        // On errors, do nothing - just terminate this client (and open a new one).
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+fn parse_frame(frame: &mut StandardSv2Frame<Message>) -> Result<PoolMessages<'_>, Sv2ClientError> {
+    let message_type = frame
+        .get_header()
+        .ok_or(Sv2ClientError::UnexpectedHandshakeFrame)?
+        .msg_type();
+    Ok((message_type, frame.payload()).try_into()?)
+}
+
+/// Sends the connection event to the update channel.
+fn send_event(update_sender: &Sender<Update<'_>>, event: ConnectionEvent) {
+    let pool = event.pool.name.clone();
+    if let Err(e) = update_sender.try_send(Update::Connection(event)) {
+        error!("Failed to send ConnectionEvent for {}: {}", pool, e);
+    }
+}
+
+fn str0255(s: String) -> Result<binary_sv2::Str0255<'static>, Sv2ClientError> {
+    s.try_into().map_err(Sv2ClientError::Encoding)
+}
+
+#[derive(Debug)]
+pub enum Sv2ClientError {
+    Io(io::Error),
+    Codec(codec_sv2::Error),
+    Parse(roles_logic_sv2::Error),
+    Encoding(binary_sv2::Error),
+    InvalidAuthorityPubkey(String),
+    UnexpectedHandshakeFrame,
+    UnexpectedMessage(u8),
+    Rejected(String),
+}
+
+impl Sv2ClientError {
+    /// The connection event recorded when the connection ends with this error.
+    fn event_kind(&self) -> ConnectionEventKind {
+        match self {
+            Sv2ClientError::Rejected(_) => ConnectionEventKind::Rejected,
+            Sv2ClientError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                ConnectionEventKind::RemoteClose
+            }
+            _ => ConnectionEventKind::ReadError,
+        }
+    }
+}
+
+impl fmt::Display for Sv2ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Sv2ClientError::Io(e) => write!(f, "I/O error: {}", e),
+            Sv2ClientError::Codec(e) => write!(f, "could not encode or decode a frame: {}", e),
+            Sv2ClientError::Parse(e) => write!(f, "could not parse a message: {:?}", e),
+            Sv2ClientError::Encoding(e) => write!(f, "could not encode a message: {:?}", e),
+            Sv2ClientError::InvalidAuthorityPubkey(e) => {
+                write!(f, "invalid authority public key: {}", e)
+            }
+            Sv2ClientError::UnexpectedHandshakeFrame => {
+                write!(f, "unexpected handshake frame after the handshake")
+            }
+            Sv2ClientError::UnexpectedMessage(t) => {
+                write!(f, "unexpected message with type {:#04x}", t)
+            }
+            Sv2ClientError::Rejected(e) => write!(f, "rejected by pool: {}", e),
+        }
+    }
+}
+
+impl error::Error for Sv2ClientError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Sv2ClientError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Sv2ClientError {
+    fn from(err: io::Error) -> Sv2ClientError {
+        Sv2ClientError::Io(err)
+    }
+}
+
+impl From<codec_sv2::Error> for Sv2ClientError {
+    fn from(err: codec_sv2::Error) -> Sv2ClientError {
+        Sv2ClientError::Codec(err)
+    }
+}
+
+impl From<codec_sv2::noise_sv2::Error> for Sv2ClientError {
+    fn from(err: codec_sv2::noise_sv2::Error) -> Sv2ClientError {
+        Sv2ClientError::Codec(err.into())
+    }
+}
+
+impl From<roles_logic_sv2::Error> for Sv2ClientError {
+    fn from(err: roles_logic_sv2::Error) -> Sv2ClientError {
+        Sv2ClientError::Parse(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Protocol as PoolProtocol;
+    use async_channel::unbounded;
+    use async_std::net::TcpListener;
+    use async_std::task;
+    use binary_sv2::{Seq0255, Sv2Option, B064K};
+    use bitcoin::secp256k1::{Keypair, Secp256k1};
+    use codec_sv2::noise_sv2::ELLSWIFT_ENCODING_SIZE;
+    use codec_sv2::Responder;
+    use roles_logic_sv2::common_messages_sv2::SetupConnectionSuccess;
+    use roles_logic_sv2::mining_sv2::{
+        NewExtendedMiningJob, OpenExtendedMiningChannelSuccess, SetNewPrevHash,
+    };
+    use std::time::Duration;
+
+    const EXTRANONCE_PREFIX: [u8; 4] = [0xde, 0xad, 0xbe, 0xef];
+    const EXTRANONCE_SIZE: u16 = 4;
+    const COINBASE_TAG: &[u8] = b"/stratum-observer-test/";
+    const HEIGHT: u32 = 850000;
+
+    /// A minimal local Stratum v2 pool accepting a single connection.
+    struct MockSv2Pool {
+        stream: TcpStream,
+        state: State,
+        encoder: NoiseEncoder<Message>,
+        decoder: StandardNoiseDecoder<Message>,
+    }
+
+    impl MockSv2Pool {
+        async fn accept(listener: &TcpListener, keypair: &Keypair) -> MockSv2Pool {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let responder = Responder::from_authority_kp(
+                &keypair.x_only_public_key().0.serialize(),
+                &keypair.secret_bytes(),
+                Duration::from_secs(3600),
+            )
+            .unwrap();
+            let mut state = State::initialized(HandshakeRole::Responder(responder));
+            let mut first_message = [0u8; ELLSWIFT_ENCODING_SIZE];
+            stream.read_exact(&mut first_message).await.unwrap();
+            let (second_message, state) = state.step_1(first_message).unwrap();
+            stream
+                .write_all(&second_message.get_payload_when_handshaking())
+                .await
+                .unwrap();
+            MockSv2Pool {
+                stream,
+                state,
+                encoder: NoiseEncoder::new(),
+                decoder: StandardNoiseDecoder::new(),
+            }
+        }
+
+        async fn send(&mut self, message: Message) {
+            let message_type = message.message_type();
+            let channel_bit = message.channel_bit();
+            let frame = Sv2Frame::from_message(message, message_type, 0, channel_bit).unwrap();
+            let encoded = self
+                .encoder
+                .encode(StandardEitherFrame::Sv2(frame), &mut self.state)
+                .unwrap();
+            self.stream.write_all(encoded.as_ref()).await.unwrap();
+        }
+
+        /// Reads a frame and returns the message type.
+        async fn read_message_type(&mut self) -> u8 {
+            loop {
+                let buffer = self.decoder.writable();
+                self.stream.read_exact(buffer).await.unwrap();
+                match self.decoder.next_frame(&mut self.state) {
+                    Ok(frame) => {
+                        let mut frame: StandardSv2Frame<Message> = frame.try_into().unwrap();
+                        return parse_frame(&mut frame).unwrap().message_type();
+                    }
+                    Err(codec_sv2::Error::MissingBytes(_)) => continue,
+                    Err(e) => panic!("could not decode frame: {:?}", e),
+                }
+            }
+        }
+    }
+
+    fn difficulty_1_target() -> [u8; 32] {
+        let mut target = [0u8; 32];
+        target[26] = 0xff;
+        target[27] = 0xff;
+        target
+    }
+
+    /// Returns the coinbase transaction split into prefix and suffix around the extranonce.
+    fn coinbase_parts() -> (Vec<u8>, Vec<u8>) {
+        use bitcoin::{
+            absolute::LockTime, transaction::Version, Amount, OutPoint, ScriptBuf, Sequence,
+            Transaction, TxIn, TxOut, Witness,
+        };
+
+        let mut script_sig = vec![0x03];
+        script_sig.extend_from_slice(&HEIGHT.to_le_bytes()[..3]);
+        script_sig.push(COINBASE_TAG.len() as u8);
+        script_sig.extend_from_slice(COINBASE_TAG);
+        let extranonce_offset = script_sig.len();
+        script_sig.extend_from_slice(&[0u8; EXTRANONCE_PREFIX.len() + EXTRANONCE_SIZE as usize]);
+
+        let coinbase = Transaction {
+            version: Version::ONE,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::from_bytes(script_sig),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(312_500_000),
+                script_pubkey: ScriptBuf::new(),
+            }],
+        };
+        let raw = bitcoin::consensus::serialize(&coinbase);
+        // version (4) + input count (1) + outpoint (36) + script_sig length (1)
+        let split = 4 + 1 + 36 + 1 + extranonce_offset;
+        let suffix_start = split + EXTRANONCE_PREFIX.len() + EXTRANONCE_SIZE as usize;
+        (raw[..split].to_vec(), raw[suffix_start..].to_vec())
+    }
+
+    #[async_std::test]
+    async fn receive_job_from_mock_sv2_pool() {
+        let secp = Secp256k1::new();
+        let keypair = Keypair::from_seckey_slice(&secp, &[0x42; 32]).unwrap();
+        let mut encoded_key = vec![1u8, 0u8];
+        encoded_key.extend_from_slice(&keypair.x_only_public_key().0.serialize());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool = Pool {
+            name: String::from("Mock SV2 Pool"),
+            endpoints: vec![listener.local_addr().unwrap().to_string()],
+            protocol: PoolProtocol::Sv2,
+            authority_pubkey: Some(bitcoin::base58::encode_check(&encoded_key)),
+            user: String::from("observer"),
+            ..Pool::default()
+        };
+
+        let (update_sender, update_receiver) = unbounded();
+        let client_pool = pool.clone();
+        task::spawn(async move {
+            let endpoint = client_pool.endpoints[0].clone();
+            Sv2Client::run(
+                &client_pool,
+                &endpoint,
+                update_sender,
+                PoolStatuses::default(),
+                ShutdownSignal::default(),
+            )
+            .await
+        });
+
+        let mut mock = MockSv2Pool::accept(&listener, &keypair).await;
+        assert_eq!(
+            mock.read_message_type().await,
+            roles_logic_sv2::parsers::CommonMessageTypes::SetupConnection as u8
+        );
+        mock.send(PoolMessages::Common(
+            CommonMessages::SetupConnectionSuccess(SetupConnectionSuccess {
+                used_version: 2,
+                flags: 0,
+            }),
+        ))
+        .await;
+        assert_eq!(
+            mock.read_message_type().await,
+            roles_logic_sv2::parsers::MiningTypes::OpenExtendedMiningChannel as u8
+        );
+        mock.send(PoolMessages::Mining(
+            Mining::OpenExtendedMiningChannelSuccess(OpenExtendedMiningChannelSuccess {
+                request_id: OPEN_CHANNEL_REQUEST_ID,
+                channel_id: 1,
+                target: U256::from(difficulty_1_target()),
+                extranonce_size: EXTRANONCE_SIZE,
+                extranonce_prefix: EXTRANONCE_PREFIX.to_vec().try_into().unwrap(),
+            }),
+        ))
+        .await;
+
+        let (coinbase_prefix, coinbase_suffix) = coinbase_parts();
+        mock.send(PoolMessages::Mining(Mining::NewExtendedMiningJob(
+            NewExtendedMiningJob {
+                channel_id: 1,
+                job_id: 7,
+                min_ntime: Sv2Option::new(None),
+                version: 0x20000000,
+                version_rolling_allowed: true,
+                merkle_path: Seq0255::new(vec![U256::from([0x11u8; 32])]).unwrap(),
+                coinbase_tx_prefix: B064K::try_from(coinbase_prefix).unwrap(),
+                coinbase_tx_suffix: B064K::try_from(coinbase_suffix).unwrap(),
+            },
+        )))
+        .await;
+        let mut prev_hash = [0u8; 32];
+        prev_hash[31] = 0x01;
+        mock.send(PoolMessages::Mining(Mining::SetNewPrevHash(
+            SetNewPrevHash {
+                channel_id: 1,
+                job_id: 7,
+                prev_hash: U256::from(prev_hash),
+                min_ntime: 1700000000,
+                nbits: 0x17034219,
+            },
+        )))
+        .await;
+
+        for kind in [
+            ConnectionEventKind::ConnectAttempt,
+            ConnectionEventKind::Connected,
+            ConnectionEventKind::Subscribed,
+        ] {
+            match future::timeout(Duration::from_secs(5), update_receiver.recv())
+                .await
+                .expect("should receive a connection event in time")
+                .unwrap()
+            {
+                Update::Connection(event) => {
+                    assert_eq!(event.kind, kind);
+                    if kind == ConnectionEventKind::Connected {
+                        assert!(event.address.is_some_and(|a| a.ip().is_loopback()));
+                    }
+                }
+                u => panic!("expected a connection event, got {:?}", u),
+            };
+        }
+        match future::timeout(Duration::from_secs(5), update_receiver.recv())
+            .await
+            .expect("should receive a difficulty update in time")
+            .unwrap()
+        {
+            Update::Difficulty(d) => {
+                assert_eq!(d.previous_difficulty, None);
+                assert_eq!(d.difficulty, 1.0);
+            }
+            u => panic!("expected a difficulty update, got {:?}", u),
+        };
+        let job = match future::timeout(Duration::from_secs(5), update_receiver.recv())
+            .await
+            .expect("should receive a job in time")
+            .unwrap()
+        {
+            Update::Job(job) => job,
+            u => panic!("expected a job update, got {:?}", u),
+        };
+        assert_eq!(job.pool, pool);
+        assert_eq!(job.job.job_id, "7");
+        assert!(job.job.clean_jobs);
+        assert_eq!(job.job.version, HexU32Be(0x20000000));
+        assert_eq!(job.version_rolling_mask, Some(0x1fffe000));
+        assert_eq!(job.endpoint, pool.endpoints[0]);
+        assert_eq!(job.job.bits, HexU32Be(0x17034219));
+        assert_eq!(job.job.time, HexU32Be(1700000000));
+        assert_eq!(job.job.merkle_branch.len(), 1);
+        assert_eq!(job.extranonce1.as_ref(), &EXTRANONCE_PREFIX);
+        assert_eq!(job.extranonce2_size, EXTRANONCE_SIZE as usize);
+        assert_eq!(job.difficulty, Some(1.0));
+        assert_eq!(job.prev_block_hash().as_ref() as &[u8], &prev_hash);
+        let coinbase_info = &job.coinbase_info;
+        assert_eq!(coinbase_info.height, HEIGHT);
+        assert_eq!(
+            coinbase_info.tag,
+            String::from_utf8(COINBASE_TAG.to_vec()).unwrap()
+        );
+        assert_eq!(coinbase_info.value_sum, 312_500_000);
+    }
+
+    #[async_std::test]
+    async fn disconnect_when_the_handshake_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool = Pool {
+            name: String::from("Silent SV2 Pool"),
+            endpoints: vec![listener.local_addr().unwrap().to_string()],
+            protocol: PoolProtocol::Sv2,
+            job_timeout: Some(1),
+            ..Pool::default()
+        };
+
+        let (update_sender, update_receiver) = unbounded();
+        let client_pool = pool.clone();
+        let client = task::spawn(async move {
+            let endpoint = client_pool.endpoints[0].clone();
+            Sv2Client::run(
+                &client_pool,
+                &endpoint,
+                update_sender,
+                PoolStatuses::default(),
+                ShutdownSignal::default(),
+            )
+            .await
+        });
+        // accept the connection, but never answer the handshake
+        let (_stream, _) = listener.accept().await.unwrap();
+
+        for kind in [
+            ConnectionEventKind::ConnectAttempt,
+            ConnectionEventKind::Connected,
+            ConnectionEventKind::JobTimeout,
+        ] {
+            match future::timeout(Duration::from_secs(5), update_receiver.recv())
+                .await
+                .expect("should receive a connection event in time")
+                .unwrap()
+            {
+                Update::Connection(event) => assert_eq!(event.kind, kind),
+                u => panic!("expected a connection event, got {:?}", u),
+            };
+        }
+        future::timeout(Duration::from_secs(5), client)
+            .await
+            .expect("the client should stop after the timeout");
+    }
+}
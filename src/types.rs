@@ -1,25 +1,190 @@
-use crate::schema::job_updates;
-use crate::utils::{bip34_coinbase_block_height, encode_hex, extract_coinbase_string};
-use bitcoin::consensus::encode::Error as ConsensusError;
+use crate::client::{
+    STRATUM_CONNECTION_MAX_LIFETIME_SECONDS, STRATUM_JOB_TIMEOUT_SECONDS, USER_AGENT,
+};
+use crate::connection::{Backoff, PartialBackoff, ReconnectPolicy};
+use crate::identify::PoolIdentification;
+use crate::schema::{
+    coinbase_outputs, connection_events, difficulty_updates, extranonce_updates, job_updates,
+    tls_updates,
+};
+use crate::transport::{TlsInfo, TransportError};
+use crate::utils::{
+    bip34_coinbase_block_height, encode_hex, extract_coinbase_string, extract_witness_commitment,
+};
 use bitcoin::hashes::sha256d::Hash;
+use bitcoin::{Address, Network, Script, Transaction, TxOut};
 use chrono::prelude::*;
 use diesel::Insertable;
 use log::warn;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::hash::{Hash as _, Hasher};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
 use sv1_api::server_to_client;
 use sv1_api::utils::Extranonce;
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+/// The stratum protocol version spoken by a pool.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Sv1,
+    Sv2,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Protocol::Sv1 => write!(f, "sv1"),
+            Protocol::Sv2 => write!(f, "sv2"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Default)]
 pub struct Pool {
     //id: u32,
     pub name: String,
-    pub endpoint: String,
-    //is_v2: bool,
+    /// The pool's host:port endpoints in failover order. A stratum+tcp:// or stratum+ssl://
+    /// (TLS) scheme can be used. A single endpoint can also be set as `endpoint`.
+    #[serde(alias = "endpoint", deserialize_with = "string_or_list")]
+    pub endpoints: Vec<String>,
+    /// The stratum protocol version used to connect to the pool. Defaults to Stratum v1.
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// Stratum v2 only: the base58check encoded authority public key of the pool used to
+    /// verify the Noise handshake. If None, the pool's certificate is not verified.
+    pub authority_pubkey: Option<String>,
+    /// stratum+ssl:// endpoints only: a PEM file with the CA certificates used to verify the
+    /// pool's certificate. If None, the Mozilla root certificates are used.
+    pub tls_ca_file: Option<String>,
+    /// stratum+ssl:// endpoints only: the hex encoded SHA-256 fingerprint of the pool's
+    /// certificate. If set, only this certificate is accepted and the CA isn't checked.
+    pub tls_certificate_fingerprint: Option<String>,
+    /// A SOCKS5 proxy (socks5://[username:password@]host:port) to connect through. Inherited
+    /// from the global proxy setting if not set.
+    pub proxy: Option<String>,
     pub user: String,
     pub password: Option<String>,
     /// Optional maximum age of the connection in seconds before we close it and open a new one.
-    /// If None, keep the connection open for as long as possible.
+    /// Inherited from the `[defaults]` if not set. If None, the connection is closed after
+    /// [STRATUM_CONNECTION_MAX_LIFETIME_SECONDS].
     pub max_lifetime: Option<u32>,
+    /// Seconds without a new job before we close the connection and open a new one.
+    /// Inherited from the `[defaults]` if not set. If None, [STRATUM_JOB_TIMEOUT_SECONDS] is
+    /// used.
+    pub job_timeout: Option<u64>,
+    /// The user agent sent in mining.subscribe or, for Stratum v2, as vendor in
+    /// SetupConnection. Inherited from the `[defaults]` if not set.
+    pub user_agent: Option<String>,
+    /// The reconnect backoff of the pool. Unset values are inherited from the global backoff.
+    #[serde(default)]
+    pub backoff: PartialBackoff,
+    /// The reconnect backoff of the pool after it rejected our credentials. Unset values are
+    /// inherited from the global rejected backoff.
+    #[serde(default)]
+    pub rejected_backoff: PartialBackoff,
+    /// Stratum v1 only: the BIP310 version-rolling mask requested in mining.configure as hex
+    /// string. Defaults to the BIP320 general purpose bits (1fffe000). Set to "00000000" to
+    /// not request version rolling.
+    pub version_rolling_mask: Option<String>,
+    /// Stratum v1 only: the BIP310 version-rolling min-bit-count requested in mining.configure.
+    pub version_rolling_min_bit_count: Option<u32>,
+    /// Stratum v1 only: write all lines sent to and received from the pool to capture files
+    /// (see [crate::capture::CaptureConfig]).
+    #[serde(default)]
+    pub capture: bool,
+    /// Stratum v1 only: which client.reconnect requests of the pool to follow. Defaults to
+    /// requests to reconnect to the same host.
+    #[serde(default)]
+    pub reconnect_policy: ReconnectPolicy,
+    /// The network the addresses of the coinbase outputs are encoded for. Inherited from the
+    /// global network setting if not set.
+    #[serde(default, deserialize_with = "deserialize_network")]
+    pub network: Option<Network>,
+}
+
+#[cfg(test)]
+impl Pool {
+    /// A Stratum v1 pool named "Test Pool" with the endpoints and the user "observer".
+    pub fn test<E: Into<String>>(endpoints: impl IntoIterator<Item = E>) -> Pool {
+        Pool {
+            name: String::from("Test Pool"),
+            endpoints: endpoints.into_iter().map(Into::into).collect(),
+            user: String::from("observer"),
+            ..Pool::default()
+        }
+    }
+}
+
+impl Pool {
+    pub fn max_lifetime(&self) -> Duration {
+        Duration::from_secs(
+            self.max_lifetime
+                .unwrap_or(STRATUM_CONNECTION_MAX_LIFETIME_SECONDS)
+                .into(),
+        )
+    }
+
+    pub fn job_timeout(&self) -> Duration {
+        Duration::from_secs(self.job_timeout.unwrap_or(STRATUM_JOB_TIMEOUT_SECONDS))
+    }
+
+    pub fn user_agent(&self) -> String {
+        self.user_agent
+            .clone()
+            .unwrap_or_else(|| USER_AGENT.to_string())
+    }
+
+    /// The pool's reconnect backoff with unset values taken from the global `backoff`.
+    pub fn backoff(&self, backoff: &Backoff) -> Backoff {
+        self.backoff.clone().or(backoff.clone())
+    }
+
+    /// The pool's rejected credentials backoff with unset values taken from the global
+    /// `rejected_backoff`.
+    pub fn rejected_backoff(&self, rejected_backoff: &Backoff) -> Backoff {
+        self.rejected_backoff.clone().or(rejected_backoff.clone())
+    }
+
+    /// The pool's network. Defaults to mainnet.
+    pub fn network(&self) -> Network {
+        self.network.unwrap_or(Network::Bitcoin)
+    }
+}
+
+/// Deserializes a network name: bitcoin, testnet, signet or regtest.
+pub fn deserialize_network<'de, D>(deserializer: D) -> Result<Option<Network>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(name) => Network::from_str(&name)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+/// Deserializes either a single string or a list of strings.
+fn string_or_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        String(String),
+        List(Vec<String>),
+    }
+    Ok(match StringOrList::deserialize(deserializer)? {
+        StringOrList::String(s) => vec![s],
+        StringOrList::List(l) => l,
+    })
 }
 
 #[derive(Insertable)]
@@ -27,6 +192,8 @@ pub struct Pool {
 pub struct NewJobUpdate {
     pub timestamp: chrono::NaiveDateTime,
     pub pool: String,
+    pub protocol: String,
+    pub endpoint: String,
     pub merkle_branches: Vec<Vec<u8>>,
     pub header_version: i64,
     pub header_bits: i64,
@@ -40,14 +207,24 @@ pub struct NewJobUpdate {
     pub coinbase_value: i64,
     pub coinbase_height: i64,
     pub coinbase_output_count: i32,
+    pub difficulty: Option<f64>,
+    pub version_rolling_mask: Option<i64>,
+    pub identified_pool: Option<String>,
+    pub identified_by: Option<String>,
+    pub pool_mismatch: bool,
+    pub witness_commitment: Option<Vec<u8>>,
+    pub witness_commitment_changed: bool,
 }
 
 impl From<JobUpdate<'_>> for NewJobUpdate {
     fn from(o: JobUpdate<'_>) -> Self {
-        let coinbase_info = o.coinbase_info();
+        let prev_hash = o.prev_block_hash();
+        let coinbase_info = *o.coinbase_info;
         NewJobUpdate {
             timestamp: o.timestamp.naive_utc(),
             pool: o.pool.name.clone(),
+            protocol: o.pool.protocol.to_string(),
+            endpoint: o.endpoint.clone(),
             merkle_branches: o
                 .job
                 .merkle_branch
@@ -57,7 +234,7 @@ impl From<JobUpdate<'_>> for NewJobUpdate {
             header_version: o.job.version.0 as i64,
             header_bits: o.job.bits.0 as i64,
             header_time: o.job.time.0 as i64,
-            header_prev_hash: o.prev_block_hash().to_string(),
+            header_prev_hash: prev_hash.to_string(),
             extranonce1: o.extranonce1.as_ref().to_vec(),
             extranonce2_size: o.extranonce2_size as i32,
             clean_jobs: o.job.clean_jobs,
@@ -66,6 +243,56 @@ impl From<JobUpdate<'_>> for NewJobUpdate {
             coinbase_tag: coinbase_info.tag,
             coinbase_height: coinbase_info.height as i64,
             coinbase_output_count: coinbase_info.output_count,
+            difficulty: o.difficulty,
+            version_rolling_mask: o.version_rolling_mask.map(|mask| mask as i64),
+            identified_pool: o.identification.as_ref().map(|i| i.name.clone()),
+            identified_by: o.identification.as_ref().map(|i| i.method.to_string()),
+            pool_mismatch: o.identification.as_ref().is_some_and(|i| i.mismatch),
+            witness_commitment: coinbase_info.witness_commitment.map(|c| c.to_vec()),
+            witness_commitment_changed: o.witness_commitment_changed,
+        }
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = coinbase_outputs)]
+pub struct NewCoinbaseOutput {
+    pub job_update_id: i32,
+    pub output_index: i32,
+    pub value: i64,
+    pub script_type: String,
+    pub address: Option<String>,
+    pub script: Vec<u8>,
+}
+
+impl NewCoinbaseOutput {
+    pub fn new(job_update_id: i32, output_index: usize, output: CoinbaseOutput) -> Self {
+        NewCoinbaseOutput {
+            job_update_id,
+            output_index: output_index as i32,
+            value: output.value as i64,
+            script_type: output.output_type.to_string(),
+            address: output.address,
+            script: output.script,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct CoinbaseOutputJson {
+    value: u64,
+    script_type: String,
+    address: Option<String>,
+    script: String,
+}
+
+impl From<CoinbaseOutput> for CoinbaseOutputJson {
+    fn from(o: CoinbaseOutput) -> Self {
+        CoinbaseOutputJson {
+            value: o.value,
+            script_type: o.output_type.to_string(),
+            address: o.address,
+            script: encode_hex(&o.script),
         }
     }
 }
@@ -73,32 +300,59 @@ impl From<JobUpdate<'_>> for NewJobUpdate {
 #[derive(Serialize)]
 pub struct JobUpdateJson {
     pool_name: String,
+    protocol: String,
+    endpoint: String,
     prev_hash: String,
     coinbase_tag: String,
     height: u32,
     coinbase_sum: u64,
+    coinbase_outputs: Vec<CoinbaseOutputJson>,
     job_timestamp: i64,
     header_version: u32,
     header_time: u32,
     header_bits: u32,
     merkle_branches: Vec<String>,
     clean_jobs: bool,
+    difficulty: Option<f64>,
+    version_rolling_mask: Option<u32>,
+    identified_pool: Option<String>,
+    identified_by: Option<String>,
+    pool_mismatch: bool,
+    witness_commitment: Option<String>,
+    witness_commitment_missing: bool,
+    witness_commitment_changed: bool,
 }
 
 impl From<JobUpdate<'_>> for JobUpdateJson {
     fn from(o: JobUpdate<'_>) -> Self {
-        let coinbase_info = o.coinbase_info();
+        let prev_hash = o.prev_block_hash();
+        let coinbase_info = *o.coinbase_info;
         JobUpdateJson {
             pool_name: o.pool.clone().name,
-            prev_hash: o.prev_block_hash().to_string(),
+            protocol: o.pool.protocol.to_string(),
+            endpoint: o.endpoint.clone(),
+            prev_hash: prev_hash.to_string(),
             coinbase_tag: coinbase_info.tag,
             height: coinbase_info.height,
             coinbase_sum: coinbase_info.value_sum,
+            coinbase_outputs: coinbase_info
+                .outputs
+                .into_iter()
+                .map(CoinbaseOutputJson::from)
+                .collect(),
             job_timestamp: o.timestamp.timestamp(),
             header_version: o.job.version.0,
             header_bits: o.job.bits.0,
             header_time: o.job.time.0,
             clean_jobs: o.job.clean_jobs,
+            difficulty: o.difficulty,
+            version_rolling_mask: o.version_rolling_mask,
+            identified_pool: o.identification.as_ref().map(|i| i.name.clone()),
+            identified_by: o.identification.as_ref().map(|i| i.method.to_string()),
+            pool_mismatch: o.identification.as_ref().is_some_and(|i| i.mismatch),
+            witness_commitment: coinbase_info.witness_commitment.map(|c| encode_hex(&c)),
+            witness_commitment_missing: coinbase_info.witness_commitment.is_none(),
+            witness_commitment_changed: o.witness_commitment_changed,
             merkle_branches: o
                 .job
                 .merkle_branch
@@ -109,56 +363,310 @@ impl From<JobUpdate<'_>> for JobUpdateJson {
     }
 }
 
-pub struct CoinbaseInfo {
-    pub height: u32,
-    pub tag: String,
-    pub value_sum: u64,
-    pub output_count: i32,
-    pub raw: Vec<u8>,
+#[derive(Serialize)]
+pub struct ConnectionEventJson {
+    pool_name: String,
+    endpoint: String,
+    event: String,
+    event_timestamp: i64,
+    address: Option<String>,
+    message: Option<String>,
+    latency_ms: Option<f64>,
 }
 
+impl From<ConnectionEvent> for ConnectionEventJson {
+    fn from(o: ConnectionEvent) -> Self {
+        ConnectionEventJson {
+            pool_name: o.pool.name,
+            endpoint: o.endpoint,
+            event: o.kind.to_string(),
+            event_timestamp: o.timestamp.timestamp(),
+            address: o.address.map(|a| a.ip().to_string()),
+            message: o.message,
+            latency_ms: o.latency.map(|l| l.as_secs_f64() * 1000.0),
+        }
+    }
+}
+
+/// An update published by a pool client.
 #[derive(Debug, Clone)]
-pub struct JobUpdate<'a> {
-    /// JobUpdate timestamp
+pub enum Update<'a> {
+    Job(JobUpdate<'a>),
+    Extranonce(ExtranonceUpdate),
+    Difficulty(DifficultyUpdate),
+    Tls(TlsUpdate),
+    Connection(ConnectionEvent),
+}
+
+/// A change of the extranonce1 and extranonce2_size during a connection, for example,
+/// by a mining.set_extranonce message.
+#[derive(Debug, Clone)]
+pub struct ExtranonceUpdate {
     pub timestamp: DateTime<Utc>,
     pub pool: Pool,
-    pub job: server_to_client::Notify<'a>,
-    pub extranonce1: Extranonce<'a>,
+    pub extranonce1: Vec<u8>,
     pub extranonce2_size: usize,
-    /// Time the client connection was established.
-    pub time_connected: DateTime<Utc>,
+    pub previous_extranonce1: Vec<u8>,
+    pub previous_extranonce2_size: usize,
 }
 
-impl JobUpdate<'_> {
-    pub fn raw_coinbase(&self) -> Vec<u8> {
-        let extranonce2 = vec![&0u8; self.extranonce2_size];
-        self.job
+#[derive(Insertable)]
+#[diesel(table_name = extranonce_updates)]
+pub struct NewExtranonceUpdate {
+    pub timestamp: chrono::NaiveDateTime,
+    pub pool: String,
+    pub extranonce1: Vec<u8>,
+    pub extranonce2_size: i32,
+    pub previous_extranonce1: Vec<u8>,
+    pub previous_extranonce2_size: i32,
+}
+
+impl From<ExtranonceUpdate> for NewExtranonceUpdate {
+    fn from(o: ExtranonceUpdate) -> Self {
+        NewExtranonceUpdate {
+            timestamp: o.timestamp.naive_utc(),
+            pool: o.pool.name,
+            extranonce1: o.extranonce1,
+            extranonce2_size: o.extranonce2_size as i32,
+            previous_extranonce1: o.previous_extranonce1,
+            previous_extranonce2_size: o.previous_extranonce2_size as i32,
+        }
+    }
+}
+
+/// A change of the share difficulty assigned to us by the pool, for example, by a
+/// mining.set_difficulty message.
+#[derive(Debug, Clone)]
+pub struct DifficultyUpdate {
+    pub timestamp: DateTime<Utc>,
+    pub pool: Pool,
+    pub difficulty: f64,
+    pub previous_difficulty: Option<f64>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = difficulty_updates)]
+pub struct NewDifficultyUpdate {
+    pub timestamp: chrono::NaiveDateTime,
+    pub pool: String,
+    pub difficulty: f64,
+    pub previous_difficulty: Option<f64>,
+}
+
+impl From<DifficultyUpdate> for NewDifficultyUpdate {
+    fn from(o: DifficultyUpdate) -> Self {
+        NewDifficultyUpdate {
+            timestamp: o.timestamp.naive_utc(),
+            pool: o.pool.name,
+            difficulty: o.difficulty,
+            previous_difficulty: o.previous_difficulty,
+        }
+    }
+}
+
+/// The negotiated TLS parameters of a new connection to a pool. Recorded for each TLS
+/// connection to be able to detect certificate changes.
+#[derive(Debug, Clone)]
+pub struct TlsUpdate {
+    pub timestamp: DateTime<Utc>,
+    pub pool: Pool,
+    pub tls: TlsInfo,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = tls_updates)]
+pub struct NewTlsUpdate {
+    pub timestamp: chrono::NaiveDateTime,
+    pub pool: String,
+    pub protocol_version: String,
+    pub cipher_suite: String,
+    pub certificate_fingerprint: String,
+}
+
+impl From<TlsUpdate> for NewTlsUpdate {
+    fn from(o: TlsUpdate) -> Self {
+        NewTlsUpdate {
+            timestamp: o.timestamp.naive_utc(),
+            pool: o.pool.name,
+            protocol_version: o.tls.protocol_version,
+            cipher_suite: o.tls.cipher_suite,
+            certificate_fingerprint: o.tls.certificate_fingerprint,
+        }
+    }
+}
+
+/// What happened to a pool connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionEventKind {
+    /// Started connecting to the endpoint.
+    ConnectAttempt,
+    /// The endpoint's host name could not be resolved.
+    ResolutionFailed,
+    /// None of the endpoint's addresses accepted the connection.
+    ConnectFailed,
+    /// The connection was opened.
+    Connected,
+    /// The pool answered our mining.subscribe or, for Stratum v2, opened the mining channel.
+    Subscribed,
+    /// The pool accepted our mining.authorize.
+    Authorized,
+    /// The pool rejected our mining.authorize or, for Stratum v2, the connection setup or
+    /// mining channel.
+    Rejected,
+    /// We closed the connection as the pool didn't send a job in time.
+    JobTimeout,
+    /// We closed the connection as it reached its max_lifetime.
+    MaxLifetime,
+    /// The pool closed the connection.
+    RemoteClose,
+    /// Reading from the connection failed.
+    ReadError,
+    /// The pool asked us to reconnect with client.reconnect and we closed the connection
+    /// to follow the request.
+    Reconnect,
+    /// The pool asked us to reconnect with client.reconnect, but the pool's reconnect
+    /// policy doesn't allow it.
+    ReconnectRefused,
+    /// The pool sent a message for the operator with client.show_message.
+    PoolMessage,
+    /// The pool sent the first job on the connection.
+    FirstJob,
+    /// We closed the connection as the observer shuts down or the pool was removed from
+    /// or changed in the configuration.
+    Shutdown,
+}
+
+impl fmt::Display for ConnectionEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectionEventKind::ConnectAttempt => write!(f, "connect_attempt"),
+            ConnectionEventKind::ResolutionFailed => write!(f, "resolution_failed"),
+            ConnectionEventKind::ConnectFailed => write!(f, "connect_failed"),
+            ConnectionEventKind::Connected => write!(f, "connected"),
+            ConnectionEventKind::Subscribed => write!(f, "subscribed"),
+            ConnectionEventKind::Authorized => write!(f, "authorized"),
+            ConnectionEventKind::Rejected => write!(f, "rejected"),
+            ConnectionEventKind::JobTimeout => write!(f, "job_timeout"),
+            ConnectionEventKind::MaxLifetime => write!(f, "max_lifetime"),
+            ConnectionEventKind::RemoteClose => write!(f, "remote_close"),
+            ConnectionEventKind::ReadError => write!(f, "read_error"),
+            ConnectionEventKind::Reconnect => write!(f, "reconnect"),
+            ConnectionEventKind::ReconnectRefused => write!(f, "reconnect_refused"),
+            ConnectionEventKind::PoolMessage => write!(f, "pool_message"),
+            ConnectionEventKind::FirstJob => write!(f, "first_job"),
+            ConnectionEventKind::Shutdown => write!(f, "shutdown"),
+        }
+    }
+}
+
+/// An event in the lifecycle of a pool connection.
+#[derive(Debug, Clone)]
+pub struct ConnectionEvent {
+    pub timestamp: DateTime<Utc>,
+    pub pool: Pool,
+    /// The pool endpoint we connect to.
+    pub endpoint: String,
+    pub kind: ConnectionEventKind,
+    /// The address we connected to. Unknown when connecting through a proxy.
+    pub address: Option<SocketAddr>,
+    /// Details, for example, the error message.
+    pub message: Option<String>,
+    /// How long the step took: the time to open the connection for Connected, the
+    /// mining.subscribe and mining.authorize round-trip times for Subscribed and Authorized,
+    /// and the time from opening the connection to the first job for FirstJob.
+    pub latency: Option<Duration>,
+}
+
+impl ConnectionEvent {
+    pub fn new(pool: &Pool, endpoint: &str, kind: ConnectionEventKind) -> ConnectionEvent {
+        ConnectionEvent {
+            timestamp: Utc::now(),
+            pool: pool.clone(),
+            endpoint: endpoint.to_string(),
+            kind,
+            address: None,
+            message: None,
+            latency: None,
+        }
+    }
+
+    pub fn with_message(self, message: String) -> ConnectionEvent {
+        ConnectionEvent {
+            message: Some(message),
+            ..self
+        }
+    }
+
+    /// The event for a connection attempt that failed with the error.
+    pub fn failed(pool: &Pool, endpoint: &str, error: &TransportError) -> ConnectionEvent {
+        let kind = match error {
+            TransportError::Resolve(..) => ConnectionEventKind::ResolutionFailed,
+            _ => ConnectionEventKind::ConnectFailed,
+        };
+        ConnectionEvent::new(pool, endpoint, kind).with_message(error.to_string())
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = connection_events)]
+pub struct NewConnectionEvent {
+    pub timestamp: chrono::NaiveDateTime,
+    pub pool: String,
+    pub endpoint: String,
+    pub kind: String,
+    pub address: Option<String>,
+    pub message: Option<String>,
+    pub latency_ms: Option<f64>,
+}
+
+impl From<ConnectionEvent> for NewConnectionEvent {
+    fn from(o: ConnectionEvent) -> Self {
+        NewConnectionEvent {
+            timestamp: o.timestamp.naive_utc(),
+            pool: o.pool.name,
+            endpoint: o.endpoint,
+            kind: o.kind.to_string(),
+            address: o.address.map(|a| a.ip().to_string()),
+            message: o.message,
+            latency_ms: o.latency.map(|l| l.as_secs_f64() * 1000.0),
+        }
+    }
+}
+
+/// A decoded coinbase transaction.
+#[derive(Debug, Clone)]
+pub struct CoinbaseInfo {
+    pub height: u32,
+    pub tag: String,
+    pub value_sum: u64,
+    pub output_count: i32,
+    pub outputs: Vec<CoinbaseOutput>,
+    /// The BIP141 witness commitment. None if the coinbase has none, which suggests an
+    /// empty or non-segwit template.
+    pub witness_commitment: Option<[u8; 32]>,
+    pub raw: Vec<u8>,
+}
+
+impl CoinbaseInfo {
+    /// Decodes the coinbase of the job with an extranonce2 of zeros. Output addresses are
+    /// encoded for the network.
+    pub fn decode(
+        job: &server_to_client::Notify,
+        extranonce1: &Extranonce,
+        extranonce2_size: usize,
+        network: Network,
+    ) -> CoinbaseInfo {
+        let extranonce2 = vec![0u8; extranonce2_size];
+        let raw: Vec<u8> = job
             .coin_base1
             .as_ref()
-            .into_iter()
-            .chain(self.extranonce1.as_ref().into_iter())
-            .chain(extranonce2.into_iter())
-            .chain(self.job.coin_base2.as_ref().into_iter())
-            .map(|b| *b)
-            .collect()
-    }
-
-    pub fn coinbase(&self) -> Result<bitcoin::Transaction, ConsensusError> {
-        let result = bitcoin::consensus::deserialize(&self.raw_coinbase());
-        if let Err(ref e) = result {
-            warn!("failed to deserialize coinbase transaction with extranonce1={} extranonce2size={}: {} - rawtx={}",
-                encode_hex(self.extranonce1.as_ref()),
-                self.extranonce2_size,
-                e,
-                encode_hex(&self.raw_coinbase()),
-            );
-        }
-        result
-    }
-
-    pub fn coinbase_info(&self) -> CoinbaseInfo {
-        let raw_coinbase = self.raw_coinbase();
-        match self.coinbase() {
+            .iter()
+            .chain(extranonce1.as_ref())
+            .chain(extranonce2.iter())
+            .chain(job.coin_base2.as_ref().iter())
+            .copied()
+            .collect();
+        match bitcoin::consensus::deserialize::<Transaction>(&raw) {
             Ok(coinbase) => {
                 let coinbase_script_sig = &coinbase
                     .input
@@ -167,25 +675,190 @@ impl JobUpdate<'_> {
                     .script_sig;
 
                 CoinbaseInfo {
-                    height: bip34_coinbase_block_height(&coinbase_script_sig).unwrap_or_default(),
+                    height: bip34_coinbase_block_height(coinbase_script_sig).unwrap_or_default(),
                     tag: extract_coinbase_string(coinbase_script_sig),
                     value_sum: coinbase
                         .output
                         .iter()
-                        .map(|o| o.value.to_sat() as u64)
+                        .map(|o| o.value.to_sat())
                         .sum::<u64>(),
                     output_count: coinbase.output.len() as i32,
-                    raw: raw_coinbase,
+                    outputs: coinbase
+                        .output
+                        .iter()
+                        .map(|o| CoinbaseOutput::new(o, network))
+                        .collect(),
+                    witness_commitment: extract_witness_commitment(&coinbase.output),
+                    raw,
+                }
+            }
+            Err(e) => {
+                warn!("failed to deserialize coinbase transaction with extranonce1={} extranonce2size={}: {} - rawtx={}",
+                    encode_hex(extranonce1.as_ref()),
+                    extranonce2_size,
+                    e,
+                    encode_hex(&raw),
+                );
+                CoinbaseInfo {
+                    height: 0,
+                    tag: format!("failed to deserialize coinbase: {}", e),
+                    value_sum: 0,
+                    output_count: 0,
+                    outputs: vec![],
+                    witness_commitment: None,
+                    raw,
                 }
             }
-            Err(e) => CoinbaseInfo {
-                height: 0,
-                tag: format!("failed to deserialize coinbase: {}", e),
-                value_sum: 0,
-                output_count: 0,
-                raw: raw_coinbase,
+        }
+    }
+}
+
+/// The merkle branches and witness commitment of each pool's last job, to detect witness
+/// commitments that change while the transactions of the template don't.
+#[derive(Debug, Default)]
+pub struct WitnessCommitments(BTreeMap<String, (Vec<MerkleBranch>, Option<[u8; 32]>)>);
+
+type MerkleBranch = Vec<u8>;
+
+impl WitnessCommitments {
+    /// Remembers the job's witness commitment. Returns true if it differs from the one of
+    /// the pool's previous job with the same merkle branches.
+    pub fn changed(&mut self, job: &JobUpdate) -> bool {
+        let merkle_branches: Vec<MerkleBranch> = job
+            .job
+            .merkle_branch
+            .iter()
+            .map(|branch| branch.as_ref().to_vec())
+            .collect();
+        let commitment = job.coinbase_info.witness_commitment;
+        match self
+            .0
+            .insert(job.pool.name.clone(), (merkle_branches.clone(), commitment))
+        {
+            Some((previous_branches, previous_commitment)) => {
+                previous_branches == merkle_branches && previous_commitment != commitment
+            }
+            None => false,
+        }
+    }
+}
+
+/// The type of a coinbase output script.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputType {
+    P2pkh,
+    P2sh,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+    OpReturn,
+    Other,
+}
+
+impl OutputType {
+    pub fn of(script: &Script) -> OutputType {
+        if script.is_p2pkh() {
+            OutputType::P2pkh
+        } else if script.is_p2sh() {
+            OutputType::P2sh
+        } else if script.is_p2wpkh() {
+            OutputType::P2wpkh
+        } else if script.is_p2wsh() {
+            OutputType::P2wsh
+        } else if script.is_p2tr() {
+            OutputType::P2tr
+        } else if script.is_op_return() {
+            OutputType::OpReturn
+        } else {
+            OutputType::Other
+        }
+    }
+}
+
+impl fmt::Display for OutputType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutputType::P2pkh => write!(f, "p2pkh"),
+            OutputType::P2sh => write!(f, "p2sh"),
+            OutputType::P2wpkh => write!(f, "p2wpkh"),
+            OutputType::P2wsh => write!(f, "p2wsh"),
+            OutputType::P2tr => write!(f, "p2tr"),
+            OutputType::OpReturn => write!(f, "op_return"),
+            OutputType::Other => write!(f, "other"),
+        }
+    }
+}
+
+/// A decoded coinbase output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoinbaseOutput {
+    pub value: u64,
+    pub output_type: OutputType,
+    /// The address paid to on the pool's network. None for OP_RETURN and other scripts
+    /// without an address.
+    pub address: Option<String>,
+    pub script: Vec<u8>,
+}
+
+impl CoinbaseOutput {
+    pub fn new(output: &TxOut, network: Network) -> CoinbaseOutput {
+        let output_type = OutputType::of(&output.script_pubkey);
+        CoinbaseOutput {
+            value: output.value.to_sat(),
+            output_type,
+            address: match output_type {
+                OutputType::OpReturn | OutputType::Other => None,
+                _ => Address::from_script(&output.script_pubkey, network)
+                    .ok()
+                    .map(|a| a.to_string()),
             },
+            script: output.script_pubkey.to_bytes(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct JobUpdate<'a> {
+    /// JobUpdate timestamp
+    pub timestamp: DateTime<Utc>,
+    pub pool: Pool,
+    /// The pool endpoint the job was received from.
+    pub endpoint: String,
+    pub job: server_to_client::Notify<'a>,
+    pub extranonce1: Extranonce<'a>,
+    pub extranonce2_size: usize,
+    /// The share difficulty assigned to us by the pool when the job was received. None if
+    /// the pool didn't set a difficulty (yet).
+    pub difficulty: Option<f64>,
+    /// The header version bits the pool allows us to roll. None if the pool doesn't allow
+    /// version rolling.
+    pub version_rolling_mask: Option<u32>,
+    /// Time the client connection was established.
+    pub time_connected: DateTime<Utc>,
+    /// The coinbase, decoded when the job is received.
+    pub coinbase_info: Box<CoinbaseInfo>,
+    /// The pool identified by the coinbase tag and output addresses. Set before the job is
+    /// stored and published if a pools.json is configured.
+    pub identification: Option<PoolIdentification>,
+    /// True if the witness commitment differs from the one of the pool's previous job with
+    /// the same merkle branches. Set before the job is stored and published.
+    pub witness_commitment_changed: bool,
+}
+
+impl JobUpdate<'_> {
+    /// A hash of the job's block template: everything but the job id, time, clean_jobs flag
+    /// and our extranonce. Equal for the same template received on different connections.
+    pub fn template_fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.prev_block_hash().hash(&mut hasher);
+        for branch in self.job.merkle_branch.iter() {
+            branch.as_ref().hash(&mut hasher);
         }
+        self.job.coin_base1.as_ref().hash(&mut hasher);
+        self.job.coin_base2.as_ref().hash(&mut hasher);
+        self.job.version.0.hash(&mut hasher);
+        self.job.bits.0.hash(&mut hasher);
+        hasher.finish()
     }
 
     pub fn prev_block_hash(&self) -> bitcoin::BlockHash {
@@ -203,3 +876,175 @@ impl JobUpdate<'_> {
         (Utc::now() - self.timestamp).num_seconds()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::decode_hex;
+    use binary_sv2::U256;
+    use bitcoin::absolute::LockTime;
+    use bitcoin::transaction::Version;
+    use bitcoin::{Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, Witness};
+    use sv1_api::utils::{HexU32Be, MerkleNode, PrevHash};
+
+    fn output(script: &str, network: Network) -> CoinbaseOutput {
+        let output = TxOut {
+            value: Amount::from_sat(1000),
+            script_pubkey: ScriptBuf::from_bytes(decode_hex(script).unwrap()),
+        };
+        CoinbaseOutput::new(&output, network)
+    }
+
+    #[test]
+    fn decode_coinbase_outputs() {
+        for (script, output_type, address) in [
+            (
+                "76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac",
+                OutputType::P2pkh,
+                Some("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa"),
+            ),
+            (
+                "a914b472a266d0bd89c13706a4132ccfb16f7c3b9fcb87",
+                OutputType::P2sh,
+                Some("3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy"),
+            ),
+            (
+                "0014751e76e8199196d454941c45d1b3a323f1433bd6",
+                OutputType::P2wpkh,
+                Some("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"),
+            ),
+            (
+                "00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262",
+                OutputType::P2wsh,
+                Some("bc1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3qccfmv3"),
+            ),
+            (
+                "5120a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c",
+                OutputType::P2tr,
+                Some("bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr"),
+            ),
+            (
+                "6a24aa21a9ede2f61c3f71d1defd3fa999dfa36953755c690689799962b48bebd836974e8cf9",
+                OutputType::OpReturn,
+                None,
+            ),
+            (
+                "2103a34b99f22c790c4e36b2b3c2c35a36db06226e41c692fc82b8b56ac1c540c5bdac",
+                OutputType::Other,
+                None,
+            ),
+        ] {
+            let output = output(script, Network::Bitcoin);
+            assert_eq!(output.output_type, output_type, "{}", script);
+            assert_eq!(output.address.as_deref(), address, "{}", script);
+            assert_eq!(encode_hex(&output.script), script);
+            assert_eq!(output.value, 1000);
+        }
+
+        let output = output(
+            "0014751e76e8199196d454941c45d1b3a323f1433bd6",
+            Network::Testnet,
+        );
+        assert_eq!(
+            output.address.as_deref(),
+            Some("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx")
+        );
+    }
+
+    /// A job whose coinbase has the outputs and an 8 byte extranonce of zeros.
+    fn job(outputs: Vec<TxOut>, merkle_branch: Vec<[u8; 32]>) -> JobUpdate<'static> {
+        let coinbase = Transaction {
+            version: Version::ONE,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::from_bytes(decode_hex("0348390d0000000000000000").unwrap()),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: outputs,
+        };
+        let coinbase = bitcoin::consensus::serialize(&coinbase);
+        let pool = Pool {
+            name: String::from("Test Pool"),
+            ..Pool::default()
+        };
+        let job = server_to_client::Notify {
+            job_id: String::from("1"),
+            prev_hash: PrevHash(U256::from([0u8; 32])),
+            coin_base1: coinbase[..46].to_vec().into(),
+            coin_base2: coinbase[54..].to_vec().into(),
+            merkle_branch: merkle_branch
+                .into_iter()
+                .map(|b| MerkleNode(U256::from(b)))
+                .collect(),
+            version: HexU32Be(0x20000000),
+            bits: HexU32Be(0x1703255b),
+            time: HexU32Be(0x66b3f3b0),
+            clean_jobs: true,
+        };
+        let extranonce1 = Extranonce::try_from(vec![0u8; 4]).unwrap();
+        let coinbase_info = Box::new(CoinbaseInfo::decode(&job, &extranonce1, 4, pool.network()));
+        JobUpdate {
+            timestamp: Utc::now(),
+            pool,
+            endpoint: String::from("127.0.0.1:3333"),
+            job,
+            extranonce1,
+            extranonce2_size: 4,
+            difficulty: None,
+            version_rolling_mask: None,
+            time_connected: Utc::now(),
+            coinbase_info,
+            identification: None,
+            witness_commitment_changed: false,
+        }
+    }
+
+    fn witness_commitment_output(commitment: u8) -> TxOut {
+        TxOut {
+            value: Amount::ZERO,
+            script_pubkey: ScriptBuf::from_bytes(
+                decode_hex(&format!(
+                    "6a24aa21a9ed{}",
+                    format!("{:02x}", commitment).repeat(32)
+                ))
+                .unwrap(),
+            ),
+        }
+    }
+
+    #[test]
+    fn extract_witness_commitment_from_job() {
+        let payout = TxOut {
+            value: Amount::from_sat(312_500_000),
+            script_pubkey: ScriptBuf::from_bytes(
+                decode_hex("76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac").unwrap(),
+            ),
+        };
+        let job_with_commitment = job(
+            vec![payout.clone(), witness_commitment_output(0x11)],
+            vec![],
+        );
+        let info = job_with_commitment.coinbase_info;
+        assert_eq!(info.height, 866632);
+        assert_eq!(info.witness_commitment, Some([0x11; 32]));
+        assert_eq!(
+            job(vec![payout], vec![]).coinbase_info.witness_commitment,
+            None
+        );
+    }
+
+    #[test]
+    fn detect_changed_witness_commitments() {
+        let mut commitments = WitnessCommitments::default();
+        let branches = vec![[0xaa; 32]];
+        assert!(!commitments.changed(&job(vec![witness_commitment_output(1)], branches.clone())));
+        assert!(!commitments.changed(&job(vec![witness_commitment_output(1)], branches.clone())));
+        // new transactions, new commitment
+        assert!(!commitments.changed(&job(vec![witness_commitment_output(2)], vec![[0xbb; 32]])));
+        // same transactions, but a different commitment
+        assert!(commitments.changed(&job(vec![witness_commitment_output(3)], vec![[0xbb; 32]])));
+        assert!(commitments.changed(&job(vec![], vec![[0xbb; 32]])));
+    }
+}
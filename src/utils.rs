@@ -1,4 +1,5 @@
 use bitcoin::blockdata::script::ScriptBuf;
+use bitcoin::TxOut;
 use std::fmt::Write;
 
 pub fn decode_hex(s: &str) -> Result<Vec<u8>, core::num::ParseIntError> {
@@ -32,7 +33,7 @@ pub fn bip34_coinbase_block_height(script: &ScriptBuf) -> Option<u32> {
         return None;
     }
     let mut array: [u8; 4] = [0; 4];
-    for (idx, b) in bytes[1..=(length as usize)].iter().enumerate() {
+    for (idx, b) in bytes[1..=length].iter().enumerate() {
         array[idx] = *b;
     }
     Some(u32::from_le_bytes(array))
@@ -42,7 +43,7 @@ pub fn extract_coinbase_string(script: &ScriptBuf) -> String {
     let mut coinbase_string = String::new();
     let mut buffer = String::new();
     for b in script.clone().into_bytes() {
-        if b >= 32 && b <= 126 {
+        if (32..=126).contains(&b) {
             buffer.push(b as char);
         } else {
             if buffer.len() >= 6 {
@@ -54,6 +55,50 @@ pub fn extract_coinbase_string(script: &ScriptBuf) -> String {
     coinbase_string
 }
 
+/// The start of a BIP141 witness commitment output script: OP_RETURN, a push of 36 bytes and
+/// the commitment header aa21a9ed.
+const WITNESS_COMMITMENT_HEADER: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+/// Extracts the BIP141 witness commitment from the coinbase outputs. If multiple outputs
+/// match, the last one is the commitment. None if there's no commitment, e.g. in templates
+/// without segwit transactions.
+pub fn extract_witness_commitment(outputs: &[TxOut]) -> Option<[u8; 32]> {
+    outputs.iter().rev().find_map(|output| {
+        let script = output.script_pubkey.as_bytes();
+        if script.len() >= 38 && script.starts_with(&WITNESS_COMMITMENT_HEADER) {
+            script[6..38].try_into().ok()
+        } else {
+            None
+        }
+    })
+}
+
+/// The target of a difficulty 1 share: 0x00000000ffff0000..0000.
+const DIFFICULTY_1_TARGET: f64 =
+    65535.0 * 411376139330301510538742295639337626245683966408394965837152256.0; // 0xffff * 2^208
+
+/// Converts a 256-bit little-endian target, as used in Stratum v2, into the share difficulty
+/// relative to the difficulty 1 target.
+pub fn target_to_difficulty(target: &[u8; 32]) -> f64 {
+    let target = target
+        .iter()
+        .rev()
+        .fold(0f64, |acc, b| acc * 256.0 + f64::from(*b));
+    DIFFICULTY_1_TARGET / target
+}
+
+/// The header version bits reserved for general purpose use by BIP320.
+pub const BIP320_VERSION_ROLLING_MASK: u32 = 0x1fffe000;
+
+/// Parses a BIP310 version-rolling mask, a big-endian hex string of at most 8 characters
+/// (e.g. "1fffe000").
+pub fn parse_version_mask(mask: &str) -> Option<u32> {
+    if mask.is_empty() || mask.len() > 8 {
+        return None;
+    }
+    u32::from_str_radix(mask, 16).ok()
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -87,6 +132,35 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_extract_witness_commitment() {
+        let output = |script: &str| TxOut {
+            value: bitcoin::Amount::ZERO,
+            script_pubkey: ScriptBuf::from_hex(script).unwrap(),
+        };
+        let payout = output("76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac");
+        let commitment = |c: &str| output(&format!("6a24aa21a9ed{}", c.repeat(32)));
+        assert_eq!(extract_witness_commitment(&[]), None);
+        assert_eq!(
+            extract_witness_commitment(std::slice::from_ref(&payout)),
+            None
+        );
+        assert_eq!(
+            extract_witness_commitment(&[payout.clone(), commitment("11")]),
+            Some([0x11; 32])
+        );
+        // the last commitment counts
+        assert_eq!(
+            extract_witness_commitment(&[commitment("11"), payout, commitment("22")]),
+            Some([0x22; 32])
+        );
+        // OP_RETURN outputs with other data or a truncated commitment aren't commitments
+        assert_eq!(
+            extract_witness_commitment(&[output("6a24b9e11b6d0000"), output("6a24aa21a9ed1111")]),
+            None
+        );
+    }
+
     #[test]
     fn test_bip34_coinbase_block_height() {
         let test_cases = vec![
@@ -130,4 +204,30 @@ mod test {
             "Hex w/ prefix not decoded correctly"
         );
     }
+
+    #[test]
+    fn test_target_to_difficulty() {
+        let mut target = [0u8; 32];
+        target[26] = 0xff;
+        target[27] = 0xff;
+        assert_eq!(target_to_difficulty(&target), 1.0);
+
+        target[27] = 0x7f;
+        target[26] = 0xff;
+        assert!((target_to_difficulty(&target) - 2.0).abs() < 0.0001);
+
+        let mut target = [0u8; 32];
+        target[25] = 0xff;
+        target[24] = 0xff;
+        assert_eq!(target_to_difficulty(&target), 65536.0);
+    }
+
+    #[test]
+    fn test_parse_version_mask() {
+        assert_eq!(parse_version_mask("1fffe000"), Some(0x1fffe000));
+        assert_eq!(parse_version_mask("00ff"), Some(0xff));
+        assert_eq!(parse_version_mask(""), None);
+        assert_eq!(parse_version_mask("1fffe0000"), None);
+        assert_eq!(parse_version_mask("xyz"), None);
+    }
 }